@@ -0,0 +1 @@
+alter table recordings drop column output_format;
@@ -0,0 +1,2 @@
+alter table recordings
+    add column output_format varchar(8) not null default 'mp4'; -- file extension of the result
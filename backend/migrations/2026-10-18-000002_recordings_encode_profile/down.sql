@@ -0,0 +1,3 @@
+alter table recordings
+    drop column encode_profile,
+    drop column encode_options;
@@ -0,0 +1,3 @@
+alter table recordings
+    add column encode_profile varchar(32) not null default 'copy', -- profile name
+    add column encode_options text not null default ''; -- FFmpeg options of the profile when clipped
@@ -0,0 +1,2 @@
+alter table recordings
+    drop column quality;
@@ -0,0 +1,2 @@
+alter table recordings
+    add column quality varchar(64) not null default 'best'; -- requested quality, kept to resume the job
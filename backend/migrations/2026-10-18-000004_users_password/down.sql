@@ -0,0 +1,5 @@
+alter table recordings
+    drop constraint recordings_user_id_fkey;
+drop index users_username;
+alter table users
+    drop column password_hash;
@@ -0,0 +1,5 @@
+alter table users
+    add column password_hash text; -- argon2 PHC string; users without one can't sign in
+create unique index users_username on users (username);
+alter table recordings
+    add constraint recordings_user_id_fkey foreign key (user_id) references users (id) on delete set null;
@@ -0,0 +1,11 @@
+create table api_tokens (
+    id serial primary key not null,
+    user_id integer not null references users (id) on delete cascade,
+    name varchar(64) not null,
+    token_hash char(64) not null unique, -- hex SHA-256 of the token, which is only shown once
+    scope varchar(16) not null default 'full', -- `full` or `clip`
+    created_at timestamp not null default now(),
+    expires_at timestamp,
+    last_used timestamp
+);
+create index api_tokens_user_id on api_tokens (user_id);
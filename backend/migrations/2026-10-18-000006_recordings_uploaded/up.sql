@@ -0,0 +1,2 @@
+alter table recordings
+    add column uploaded boolean not null default true; -- false when the result is kept in the temp directory
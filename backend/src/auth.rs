@@ -0,0 +1,633 @@
+//! Users signing in with HTTP Basic authentication or API tokens (`Authorization: Bearer`)
+
+use crate::{
+    clip::FieldError,
+    config::CONFIG,
+    database::{Database, NewUser, PoolPg, Recording, User, UserId},
+    error::ApiError,
+};
+
+use std::{fmt::Write as _, io::BufRead as _};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore as _},
+        PasswordHasher as _, PasswordVerifier as _, SaltString,
+    },
+    Argon2, PasswordHash,
+};
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine as _,
+};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::task::spawn_blocking;
+use warp::Filter;
+
+lazy_static! {
+    /// Checked when there's no password to verify, so unknown usernames take as long to reject
+    /// as wrong passwords and can't be told apart by timing
+    static ref DUMMY_PASSWORD_HASH: String =
+        hash_password("dummy").expect("failed to hash the dummy password");
+}
+
+/// `[auth]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Let requests without credentials clip, limited by `[clips] max_length_seconds`
+    pub allow_anonymous: bool,
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            allow_anonymous: true,
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("failed to hash password: {e}"))?
+        .to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Start of every API token, so they're recognisable in scripts and secret scanners
+const TOKEN_PREFIX: &str = "bbcd_";
+
+/// What an API token can do
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Everything its user can do
+    #[default]
+    Full,
+    /// Submit, follow and cancel clips
+    Clip,
+}
+impl TokenScope {
+    /// Value stored on the token row
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Clip => "clip",
+        }
+    }
+    /// Scope of a token row, restricting unknown scopes to clipping
+    fn from_row(scope: &str) -> Self {
+        match scope {
+            "full" => Self::Full,
+            _ => Self::Clip,
+        }
+    }
+    /// Drop the permissions of a user that the scope doesn't grant
+    fn restrict(self, user: &mut User) {
+        if self == Self::Clip {
+            user.can_delete = false;
+            user.superuser = false;
+        }
+    }
+}
+
+/// Body of `POST /tokens`
+#[derive(Deserialize)]
+pub struct TokenParameters {
+    pub name: String,
+    #[serde(default)]
+    pub scope: TokenScope,
+    /// Days until the token stops working; never when unset
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+impl TokenParameters {
+    /// When the token stops working, rejecting expiries past the representable dates
+    pub fn expires_at(&self) -> Result<Option<NaiveDateTime>, ApiError> {
+        let Some(days) = self.expires_in_days else {
+            return Ok(None);
+        };
+        TimeDelta::try_days(days.into())
+            .and_then(|expires_in| Utc::now().checked_add_signed(expires_in))
+            .map(|expires_at| Some(expires_at.naive_utc()))
+            .ok_or_else(|| {
+                ApiError::invalid_fields(vec![FieldError {
+                    field: "expires_in_days",
+                    message: format!("{days} days is too far in the future"),
+                }])
+            })
+    }
+}
+
+/// A new random API token
+pub fn generate_token() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{TOKEN_PREFIX}{}", BASE64_URL.encode(bytes))
+}
+
+/// Hex SHA-256 stored in place of a token. Tokens are random enough that a slow hash isn't needed.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Credentials of an `Authorization` header
+enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+impl Credentials {
+    fn parse(authorization: &str) -> Option<Self> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return Some(Self::Bearer(credentials.to_string()));
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let credentials = String::from_utf8(BASE64.decode(credentials).ok()?).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        Some(Self::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Find the user with these credentials
+async fn authenticate(
+    database: &mut Database,
+    username: &str,
+    password: String,
+) -> Result<Option<User>, ApiError> {
+    let user = database
+        .get_user_by_name(username)
+        .map_err(ApiError::internal)?;
+    let hash = user.as_ref().and_then(|user| user.password_hash.clone());
+    // Hashing takes long enough to stall the runtime
+    let verified = spawn_blocking(move || match hash {
+        Some(hash) => verify_password(&hash, &password),
+        None => {
+            verify_password(&DUMMY_PASSWORD_HASH, &password);
+            false
+        }
+    })
+    .await
+    .map_err(ApiError::internal)?;
+    Ok(user.filter(|_| verified))
+}
+
+/// Find the user of an API token, limited to the token's scope
+async fn authenticate_token(database: &mut Database, token: &str) -> Result<User, ApiError> {
+    let (token, mut user) = database
+        .get_api_token_by_hash(&hash_token(token))
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::unauthorized("unknown or revoked API token"))?;
+    if token
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc())
+    {
+        return Err(ApiError::unauthorized(format!(
+            "API token {name} has expired",
+            name = token.name
+        )));
+    }
+    if let Err(e) = database.touch_api_token(token.id) {
+        warn!(
+            "failed to update when API token {id} was last used: {e}",
+            id = token.id
+        );
+    }
+    TokenScope::from_row(&token.scope).restrict(&mut user);
+    Ok(user)
+}
+
+/// Resolve the caller of a request, `None` for anonymous requests.
+/// The database is only used once there are credentials to check.
+async fn resolve_user(
+    pool: &PoolPg,
+    authorization: Option<String>,
+    allow_anonymous: bool,
+    allow_tokens: bool,
+) -> Result<Option<User>, ApiError> {
+    let Some(authorization) = authorization else {
+        if allow_anonymous {
+            return Ok(None);
+        }
+        return Err(ApiError::unauthorized("sign in required"));
+    };
+    match Credentials::parse(&authorization) {
+        Some(Credentials::Basic { username, password }) => {
+            let mut database = Database::connect(pool)?;
+            match authenticate(&mut database, &username, password).await? {
+                Some(user) => Ok(Some(user)),
+                None => Err(ApiError::unauthorized("wrong username or password")),
+            }
+        }
+        Some(Credentials::Bearer(_)) if !allow_tokens => Err(ApiError::forbidden(
+            "API tokens can't do this, sign in with a password",
+        )),
+        Some(Credentials::Bearer(token)) => {
+            let mut database = Database::connect(pool)?;
+            authenticate_token(&mut database, &token).await.map(Some)
+        }
+        None => Err(ApiError::unauthorized(
+            "unsupported authorization, use Basic or Bearer",
+        )),
+    }
+}
+
+/// Filter resolving the caller from the `Authorization` header, `None` for anonymous requests
+pub fn with_user(
+    pool: PoolPg,
+) -> impl Filter<Extract = (Option<User>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(|authorization: Option<String>, pool: PoolPg| async move {
+            let user =
+                resolve_user(&pool, authorization, CONFIG.auth.allow_anonymous, true).await?;
+            Ok::<_, warp::Rejection>(user)
+        })
+}
+
+/// Filter requiring a signed-in user
+pub fn with_signed_in_user(
+    pool: PoolPg,
+) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
+    with_user(pool).and_then(|user: Option<User>| async move {
+        user.ok_or_else(|| warp::reject::custom(ApiError::unauthorized("sign in required")))
+    })
+}
+
+/// Filter requiring a user signed in with a password rather than an API token
+pub fn with_password_user(
+    pool: PoolPg,
+) -> impl Filter<Extract = (UserId,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(|authorization: Option<String>, pool: PoolPg| async move {
+            let user = resolve_user(&pool, authorization, false, false)
+                .await?
+                .ok_or_else(|| ApiError::unauthorized("sign in required"))?;
+            Ok::<_, warp::Rejection>(user.id)
+        })
+}
+
+/// What a user needs a flag of the `users` table for
+#[derive(Clone, Copy, Debug)]
+pub enum Permission {
+    /// Have clip results uploaded to storage rather than kept in the temp directory
+    Upload,
+    Delete,
+    /// Manage other users' clips and inspect the server
+    Admin,
+}
+impl User {
+    /// Superusers can do everything
+    pub fn can(&self, permission: Permission) -> bool {
+        self.superuser
+            || match permission {
+                Permission::Upload => self.can_upload,
+                Permission::Delete => self.can_delete,
+                Permission::Admin => false,
+            }
+    }
+}
+
+/// Check that the caller is signed in with `permission`
+pub fn authorize(user: Option<&User>, permission: Permission) -> Result<&User, ApiError> {
+    let user = user.ok_or_else(|| ApiError::unauthorized("sign in required"))?;
+    if !user.can(permission) {
+        return Err(ApiError::forbidden(format!(
+            "{username} isn't allowed to {action}",
+            username = user.username,
+            action = match permission {
+                Permission::Upload => "upload clips",
+                Permission::Delete => "delete recordings",
+                Permission::Admin => "do that",
+            }
+        )));
+    }
+    Ok(user)
+}
+
+/// Check that the caller owns the recording or is a superuser
+pub fn authorize_owner(user: Option<&User>, recording: &Recording) -> Result<(), ApiError> {
+    let user = user.ok_or_else(|| ApiError::unauthorized("sign in required"))?;
+    if recording.user_id != Some(user.id) && !user.can(Permission::Admin) {
+        return Err(ApiError::forbidden(format!(
+            "{uuid} belongs to another user",
+            uuid = recording.uuid.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Filter requiring a signed-in user with `permission`
+pub fn with_permission(
+    pool: PoolPg,
+    permission: Permission,
+) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
+    with_signed_in_user(pool).and_then(move |user: User| async move {
+        authorize(Some(&user), permission)?;
+        Ok::<_, warp::Rejection>(user)
+    })
+}
+
+/// `add-user <username> [--superuser]`: create a user with the password read from stdin
+pub fn add_user_command(mut database: Database, args: &[String]) -> Result<()> {
+    let (username, superuser) = match args {
+        [username] => (username, false),
+        [username, flag] if flag == "--superuser" => (username, true),
+        _ => bail!("usage: add-user <username> [--superuser]"),
+    };
+    if username.is_empty() || username.len() > 32 || username.contains(':') {
+        bail!("usernames are 1 to 32 characters without colons");
+    }
+    if database.get_user_by_name(username)?.is_some() {
+        bail!("{username} already exists");
+    }
+
+    eprintln!("password for {username}:");
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .context("failed to read password")?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        bail!("the password can't be empty");
+    }
+
+    let user = database.create_user(&NewUser {
+        username: username.clone(),
+        password_hash: Some(hash_password(password)?),
+        superuser,
+    })?;
+    info!("created user {username} with id {id}", id = user.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::database::test::{pool as test_pool, unreachable_pool};
+
+    fn user(id: UserId) -> User {
+        User {
+            id,
+            username: format!("user{id}"),
+            max_length_seconds: 3600,
+            can_upload: true,
+            can_delete: false,
+            superuser: false,
+            password_hash: None,
+        }
+    }
+
+    fn superuser(id: UserId) -> User {
+        User {
+            superuser: true,
+            can_upload: false,
+            ..user(id)
+        }
+    }
+
+    fn recording(user_id: Option<UserId>) -> Recording {
+        Recording {
+            id: 1,
+            user_id,
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            rec_start: NaiveDateTime::default(),
+            rec_end: NaiveDateTime::default(),
+            status: String::new(),
+            short_status: String::new(),
+            stage: 0,
+            channel: "bbc_one_hd".to_string(),
+            output_format: "mp4".to_string(),
+            encode_profile: "copy".to_string(),
+            encode_options: String::new(),
+            quality: "best".to_string(),
+            uploaded: true,
+        }
+    }
+
+    #[test]
+    fn permissions_follow_user_flags() {
+        let mut user = user(1);
+        assert!(user.can(Permission::Upload));
+        assert!(!user.can(Permission::Delete));
+        assert!(!user.can(Permission::Admin));
+
+        user.can_upload = false;
+        user.can_delete = true;
+        assert!(!user.can(Permission::Upload));
+        assert!(user.can(Permission::Delete));
+        assert!(!user.can(Permission::Admin));
+
+        let superuser = superuser(2);
+        for permission in [Permission::Upload, Permission::Delete, Permission::Admin] {
+            assert!(superuser.can(permission), "{permission:?}");
+        }
+    }
+
+    #[test]
+    fn authorize_requires_permission() {
+        assert!(matches!(
+            authorize(None, Permission::Upload),
+            Err(ApiError::Unauthorized(_))
+        ));
+        let user = user(1);
+        assert_eq!(authorize(Some(&user), Permission::Upload).unwrap().id, 1);
+        assert!(matches!(
+            authorize(Some(&user), Permission::Delete),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            authorize(Some(&user), Permission::Admin),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(authorize(Some(&superuser(2)), Permission::Admin).is_ok());
+    }
+
+    #[test]
+    fn authorize_owner_allows_owner_and_superusers() {
+        let owned = recording(Some(1));
+        assert!(authorize_owner(Some(&user(1)), &owned).is_ok());
+        assert!(matches!(
+            authorize_owner(Some(&user(2)), &owned),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(authorize_owner(Some(&superuser(2)), &owned).is_ok());
+        assert!(matches!(
+            authorize_owner(None, &owned),
+            Err(ApiError::Unauthorized(_))
+        ));
+
+        // Anonymous clips only belong to superusers
+        let anonymous = recording(None);
+        assert!(matches!(
+            authorize_owner(Some(&user(1)), &anonymous),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            authorize_owner(None, &anonymous),
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(authorize_owner(Some(&superuser(2)), &anonymous).is_ok());
+    }
+
+    #[test]
+    fn token_scopes_restrict_users() {
+        let mut full = User {
+            can_delete: true,
+            ..superuser(1)
+        };
+        TokenScope::Full.restrict(&mut full);
+        assert!(full.superuser && full.can_delete);
+
+        let mut clip = full.clone();
+        TokenScope::Clip.restrict(&mut clip);
+        assert!(!clip.superuser && !clip.can_delete);
+        assert_eq!(clip.can_upload, full.can_upload);
+        assert!(!clip.can(Permission::Admin));
+
+        assert_eq!(TokenScope::from_row("full"), TokenScope::Full);
+        assert_eq!(TokenScope::from_row("clip"), TokenScope::Clip);
+        assert_eq!(TokenScope::from_row("unknown"), TokenScope::Clip);
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let basic = format!("Basic {}", BASE64.encode("alice:pass:word"));
+        assert!(matches!(
+            Credentials::parse(&basic),
+            Some(Credentials::Basic { username, password })
+                if username == "alice" && password == "pass:word"
+        ));
+        assert!(matches!(
+            Credentials::parse("bearer  bbcd_abc "),
+            Some(Credentials::Bearer(token)) if token == "bbcd_abc"
+        ));
+        for authorization in ["Basic !!!", "Basic", "Digest abc", ""] {
+            assert!(
+                Credentials::parse(authorization).is_none(),
+                "parsed {authorization}"
+            );
+        }
+    }
+
+    #[test]
+    fn token_expiry_is_bounded() {
+        let parameters = |expires_in_days| TokenParameters {
+            name: "test".to_string(),
+            scope: TokenScope::Full,
+            expires_in_days,
+        };
+        assert_eq!(parameters(None).expires_at().unwrap(), None);
+        let expires_at = parameters(Some(30)).expires_at().unwrap().unwrap();
+        let expires_in = expires_at - Utc::now().naive_utc();
+        assert!(expires_in > TimeDelta::days(29) && expires_in <= TimeDelta::days(30));
+        assert!(matches!(
+            parameters(Some(u32::MAX)).expires_at(),
+            Err(ApiError::Validation { fields, .. }) if fields[0].field == "expires_in_days"
+        ));
+    }
+
+    #[test]
+    fn hashes_tokens() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, generate_token());
+        let hash = hash_token(&token);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_token(&token));
+        assert_ne!(hash, hash_token(&generate_token()));
+    }
+
+    #[tokio::test]
+    async fn resolves_callers_without_credentials() {
+        // None of these need the database
+        let pool = unreachable_pool();
+        assert!(resolve_user(&pool, None, true, true)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            resolve_user(&pool, None, false, true).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            resolve_user(&pool, Some("Digest abc".to_string()), true, true).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            resolve_user(&pool, Some("Bearer bbcd_abc".to_string()), true, false).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        // Credentials are checked against the database
+        assert!(matches!(
+            resolve_user(&pool, Some("Bearer bbcd_abc".to_string()), true, true).await,
+            Err(ApiError::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_real_ones() {
+        let real = hash_password("password").unwrap();
+        let (dummy, real) = (
+            PasswordHash::new(&DUMMY_PASSWORD_HASH).unwrap(),
+            PasswordHash::new(&real).unwrap(),
+        );
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn authenticates_passwords() {
+        let mut database = Database::connect(&test_pool()).unwrap();
+        let new_user = |password_hash| NewUser {
+            username: generate_token()[..32].to_string(),
+            password_hash,
+            superuser: false,
+        };
+        let with_password = database
+            .create_user(&new_user(Some(hash_password("hunter2").unwrap())))
+            .unwrap();
+        let without_password = database.create_user(&new_user(None)).unwrap();
+
+        for (username, password, expected) in [
+            (&with_password.username, "hunter2", Some(with_password.id)),
+            (&with_password.username, "hunter3", None),
+            (&without_password.username, "", None),
+            (&"nobody".to_string(), "hunter2", None),
+        ] {
+            let user = authenticate(&mut database, username, password.to_string())
+                .await
+                .unwrap();
+            assert_eq!(user.map(|user| user.id), expected, "{username}:{password}");
+        }
+    }
+}
@@ -1,36 +1,45 @@
 use crate::{
-    consts::{SourceEntry, SOURCES, WEBDAV_PASSWORD, WEBDAV_URL, WEBDAV_USERNAME},
-    database::{Database, Recording, RecordingUpdate, Uuid},
+    auth::Permission,
+    config::{EncodeProfile, CONFIG, COPY_PROFILE, ENCODE_PROFILE},
+    database::{Database, PoolPg, Recording, RecordingUpdate, User, UserId, Uuid},
+    dvr, health,
+    manifest::{ContentType, Manifest, Quality, Representation},
+    segment_cache, sources,
+    storage::{clip_key, upload_file, Storage},
     websocket_callbacks::alert_clients_of_database_change,
     ClientConnections, PORT,
 };
 
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Reverse,
+    collections::HashMap,
     path::{Path, PathBuf},
     process::Stdio,
     sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, TimeDelta};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
 use ffmpeg_cli::{FfmpegBuilder, Parameter};
-use futures_util::{join, stream, StreamExt, TryStreamExt as _};
+use futures_util::{future::try_join_all, stream, StreamExt, TryStreamExt as _};
 use lazy_static::lazy_static;
-use log::{debug, error, info, trace};
-use serde::Deserialize;
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use tokio::{
     fs::{create_dir_all, remove_dir_all, remove_file, File},
     io::AsyncWriteExt as _,
     process::Command,
     sync::{
-        mpsc::{unbounded_channel, UnboundedSender},
-        Mutex, RwLock,
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex, RwLock, Semaphore,
     },
     time::{sleep, Instant},
 };
+use tokio_util::sync::CancellationToken;
 use warp::reply::Reply;
 
+#[derive(Debug, PartialEq)]
 enum JobQueueReport {
     Ready,
     InQueue(usize),
@@ -45,16 +54,201 @@ enum ShortStatus {
 
 /// `None` signifies the end of an FFmpeg job
 pub type FfmpegProgressChannels = Arc<RwLock<HashMap<Uuid, UnboundedSender<Option<TimeDelta>>>>>;
-type JobQueueChannel = RwLock<VecDeque<(Uuid, UnboundedSender<JobQueueReport>)>>;
 
-const VIDEO_DOWNLOAD_JOB_COUNT: usize = 10;
-const TEMP_DIRECTORY: &str = "temp";
+pub const TEMP_DIRECTORY: &str = "temp";
 const INIT_DIRECTORY: &str = "init";
 
 lazy_static! {
-    static ref JOB_QUEUE_CHANNEL: JobQueueChannel = RwLock::new(VecDeque::new());
+    static ref JOB_QUEUE: Mutex<JobQueue> = Mutex::new(JobQueue::default());
+    /// Limits the FFmpeg jobs combining and encoding clips at once
+    static ref ENCODE_SEMAPHORE: Semaphore = Semaphore::new(CONFIG.workers.encodes);
+    /// Client for manifest and segment downloads; connections are reused across requests
+    pub static ref DOWNLOAD_CLIENT: reqwest::Client = reqwest::ClientBuilder::new()
+        .connect_timeout(Duration::from_secs(CONFIG.download.connect_timeout_seconds))
+        .read_timeout(Duration::from_secs(CONFIG.download.read_timeout_seconds))
+        .build()
+        .expect("failed to build download client");
+    /// Tokens of the clips that are queued or running
+    static ref CANCELLATION_TOKENS: Mutex<HashMap<Uuid, CancellationToken>> =
+        Mutex::new(HashMap::new());
+}
+
+/// `[workers]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Clips processed at once
+    pub jobs: usize,
+    /// Segments downloaded at once by each clip
+    pub downloads_per_job: usize,
+    /// FFmpeg combine/encode jobs run at once across all clips
+    pub encodes: usize,
+    /// Clips this long or shorter jump ahead of longer ones in the queue (`0` to disable)
+    pub short_clip_seconds: usize,
+    /// Hours the files of failed clips, and results that weren't uploaded, are kept
+    pub retention_hours: u64,
+}
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            downloads_per_job: 10,
+            encodes: 1,
+            short_clip_seconds: 300,
+            retention_hours: 24,
+        }
+    }
+}
+impl WorkerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.jobs == 0 || self.downloads_per_job == 0 || self.encodes == 0 {
+            bail!("worker counts must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// `[clips]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipConfig {
+    /// Longest clip that can be requested without a user's own limit
+    pub max_length_seconds: usize,
+}
+impl Default for ClipConfig {
+    fn default() -> Self {
+        // Same as the `users.max_length_seconds` default
+        Self {
+            max_length_seconds: 3600,
+        }
+    }
+}
+
+/// Scheduling priority of a clip; higher priorities start first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Priority {
+    Normal,
+    High,
+}
+
+/// A clip job waiting for a worker slot
+struct QueuedJob {
+    uuid: Uuid,
+    user_id: Option<UserId>,
+    priority: Priority,
+    report: UnboundedSender<JobQueueReport>,
+    /// Last position reported to the job
+    position: usize,
+}
+
+/// `[download]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Passes through a source's CDNs after the first before a segment fails
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every retry after
+    pub retry_delay_ms: u64,
+    pub connect_timeout_seconds: u64,
+    /// Longest wait for a response or more of its body before giving up on the CDN
+    pub read_timeout_seconds: u64,
+}
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            retry_delay_ms: 500,
+            connect_timeout_seconds: 10,
+            read_timeout_seconds: 15,
+        }
+    }
+}
+
+/// Clip jobs holding one of the worker slots or waiting for one
+#[derive(Default)]
+struct JobQueue {
+    /// Jobs holding a slot along with their users
+    running: Vec<(Uuid, Option<UserId>)>,
+    /// Waiting jobs in order of arrival
+    waiting: Vec<QueuedJob>,
+}
+impl JobQueue {
+    /// Add a job; it's told through `report` once it may start, or its position until then
+    fn join(
+        &mut self,
+        uuid: Uuid,
+        user_id: Option<UserId>,
+        priority: Priority,
+        report: UnboundedSender<JobQueueReport>,
+        workers: usize,
+    ) {
+        self.waiting.push(QueuedJob {
+            uuid,
+            user_id,
+            priority,
+            report,
+            position: 0,
+        });
+        self.schedule(workers);
+    }
+    /// Remove a running or waiting job, handing out free slots
+    fn leave(&mut self, uuid: &str, workers: usize) -> Result<()> {
+        if let Some(idx) = self.running.iter().position(|(running, _)| running == uuid) {
+            self.running.swap_remove(idx);
+        } else if let Some(idx) = self.waiting.iter().position(|job| job.uuid == uuid) {
+            self.waiting.remove(idx);
+        } else {
+            bail!("{uuid} isn't in the queue");
+        }
+        self.schedule(workers);
+        Ok(())
+    }
+
+    /// Indices of the waiting jobs in the order they will start: by priority, then taking
+    /// turns between users (counting the jobs they already have running), then by arrival
+    fn scheduling_order(&self) -> Vec<usize> {
+        let mut turns = HashMap::<(Option<UserId>, Priority), usize>::new();
+        let mut order = self
+            .waiting
+            .iter()
+            .enumerate()
+            .map(|(idx, job)| {
+                let turn = turns.entry((job.user_id, job.priority)).or_insert_with(|| {
+                    self.running
+                        .iter()
+                        .filter(|(_, user_id)| *user_id == job.user_id)
+                        .count()
+                });
+                *turn += 1;
+                (Reverse(job.priority), *turn, idx)
+            })
+            .collect::<Vec<_>>();
+        order.sort_unstable();
+        order.into_iter().map(|(_, _, idx)| idx).collect()
+    }
+    /// Start the jobs next in line while there are free slots and report new positions
+    /// to the jobs still waiting
+    fn schedule(&mut self, workers: usize) {
+        while self.running.len() < workers {
+            let Some(&next) = self.scheduling_order().first() else {
+                break;
+            };
+            let job = self.waiting.remove(next);
+            // A job that went away while waiting doesn't take the slot
+            if job.report.send(JobQueueReport::Ready).is_ok() {
+                self.running.push((job.uuid, job.user_id));
+            }
+        }
+        for (position, idx) in self.scheduling_order().into_iter().enumerate() {
+            let job = &mut self.waiting[idx];
+            if job.position != position + 1 {
+                job.position = position + 1;
+                debug!("{uuid}: now at queue pos {}", job.position, uuid = job.uuid);
+                let _ = job.report.send(JobQueueReport::InQueue(job.position));
+            }
+        }
+    }
 }
-const MAX_JOB_COUNT: usize = 1;
 
 struct StatusReporter {
     pub clients: ClientConnections,
@@ -94,7 +288,173 @@ pub struct ClipParameters {
     pub start_timestamp: usize,
     pub end_timestamp: usize,
     pub channel: String,
+    /// Legacy switch selecting the `x264` profile when `profile` isn't set
+    #[serde(default)]
     pub encode: bool,
+    /// Name of the encode profile from the configuration
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Video quality (e.g. `1080p50`, `720p`, `audio-only`, or a representation ID);
+    /// defaults to the best
+    #[serde(default)]
+    pub quality: Quality,
+    /// Container for `audio-only` clips; defaults to `m4a`
+    #[serde(default)]
+    pub audio_format: Option<AudioFormat>,
+}
+
+impl ClipParameters {
+    /// Name of the encode profile this request asks for
+    pub fn profile_name(&self) -> &str {
+        match (&self.profile, self.encode) {
+            (Some(profile), _) => profile,
+            (None, true) => ENCODE_PROFILE,
+            (None, false) => COPY_PROFILE,
+        }
+    }
+
+    /// Check the request against the channel's manifest (`None` for an unknown channel),
+    /// returning what's wrong with each invalid field
+    pub fn validate(
+        &self,
+        manifest: Option<&Manifest>,
+        max_length_seconds: usize,
+    ) -> Vec<FieldError> {
+        let mut errors = vec![];
+        let mut error = |field, message: String| errors.push(FieldError { field, message });
+        let now = Utc::now().timestamp() as usize;
+        let (start, end) = (self.start_timestamp, self.end_timestamp);
+
+        if let Err(e) = CONFIG.profile(self.profile_name()) {
+            error("profile", e.to_string());
+        }
+        if self.audio_format.is_some() && self.quality != Quality::AudioOnly {
+            error(
+                "audio_format",
+                "is only allowed with quality audio-only".to_string(),
+            );
+        }
+        if end <= start {
+            error("end_timestamp", "must be after start_timestamp".to_string());
+        } else if end - start > max_length_seconds {
+            error(
+                "end_timestamp",
+                format!("clips can be at most {max_length_seconds} seconds long"),
+            );
+        }
+        if end > now {
+            error("end_timestamp", "is in the future".to_string());
+        }
+
+        let Some(manifest) = manifest else {
+            error("channel", format!("unknown channel {}", self.channel));
+            return errors;
+        };
+        if let Err(e) = manifest.select(&self.quality) {
+            error("quality", e.to_string());
+        }
+        let mut oldest = manifest.availability_start_time.timestamp().max(0) as usize;
+        if let Some(buffer) = manifest.time_shift_buffer_depth {
+            let mut depth = buffer.num_seconds().max(0) as usize;
+            if CONFIG.dvr.channels.contains(&self.channel) {
+                depth = depth.max((CONFIG.dvr.depth_hours * 60. * 60.) as usize);
+            }
+            oldest = oldest.max(now.saturating_sub(depth));
+        }
+        if start < oldest {
+            error(
+                "start_timestamp",
+                format!("is before the oldest available segment at {oldest}"),
+            );
+        }
+        errors
+    }
+}
+
+/// Why a request field was rejected
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Output container for audio-only clips
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    /// Remux of the source AAC
+    #[default]
+    M4a,
+    Mp3,
+    Opus,
+}
+
+/// Container of a clip's result
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Mp4,
+    Mkv,
+    Mov,
+    M4a,
+    Mp3,
+    Opus,
+}
+impl OutputFormat {
+    /// File extension, which is also what's stored on the recording row
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Mov => "mov",
+            Self::M4a => "m4a",
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+        }
+    }
+    /// Inverse of [`Self::extension`]
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        [
+            Self::Mp4,
+            Self::Mkv,
+            Self::Mov,
+            Self::M4a,
+            Self::Mp3,
+            Self::Opus,
+        ]
+        .into_iter()
+        .find(|format| format.extension() == extension)
+    }
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Mp4 => "video/mp4",
+            Self::Mkv => "video/x-matroska",
+            Self::Mov => "video/quicktime",
+            Self::M4a => "audio/mp4",
+            Self::Mp3 => "audio/mpeg",
+            Self::Opus => "audio/ogg",
+        }
+    }
+    pub fn is_audio_only(self) -> bool {
+        matches!(self, Self::M4a | Self::Mp3 | Self::Opus)
+    }
+    /// FFmpeg output options for the audio stream of audio-only clips
+    fn audio_options(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Mp4 | Self::Mkv | Self::Mov | Self::M4a => &[("c:a", "copy")],
+            Self::Mp3 => &[("c:a", "libmp3lame"), ("q:a", "2")],
+            Self::Opus => &[("c:a", "libopus"), ("b:a", "128k")],
+        }
+    }
+}
+impl From<AudioFormat> for OutputFormat {
+    fn from(value: AudioFormat) -> Self {
+        match value {
+            AudioFormat::M4a => Self::M4a,
+            AudioFormat::Mp3 => Self::Mp3,
+            AudioFormat::Opus => Self::Opus,
+        }
+    }
 }
 
 /// Clip progress stage
@@ -120,6 +480,8 @@ pub enum Stage {
     CombiningFailed = 12,
     EncodingFailed = 13,
     UploadingFailed = 14,
+    /// Stopped on request
+    Cancelled = 15,
 }
 impl Stage {
     pub fn error_variant(self) -> Self {
@@ -131,12 +493,150 @@ impl Stage {
             _ => Self::FailedNondescript,
         }
     }
+    /// Stage to restart from after failing with this stage
+    pub fn retry_variant(self) -> Self {
+        match self {
+            Self::DownloadingFailed => Self::Downloading,
+            Self::CombiningFailed => Self::Combining,
+            Self::EncodingFailed => Self::Encoding,
+            Self::UploadingFailed => Self::Uploading,
+            _ => Self::WaitingQueue,
+        }
+    }
+    pub fn is_failure(self) -> bool {
+        (Self::FailedNondescript as usize..=Self::UploadingFailed as usize)
+            .contains(&(self as usize))
+    }
+    /// Whether no job is working on the clip anymore
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Complete | Self::Cancelled) || self.is_failure()
+    }
+    /// Stage stored in a recording row
+    pub fn from_row(stage: i32) -> Option<Self> {
+        [
+            Self::WaitingQueue,
+            Self::Initializing,
+            Self::Downloading,
+            Self::Combining,
+            Self::Encoding,
+            Self::Uploading,
+            Self::Complete,
+            Self::FailedNondescript,
+            Self::DownloadingFailed,
+            Self::CombiningFailed,
+            Self::EncodingFailed,
+            Self::UploadingFailed,
+            Self::Cancelled,
+        ]
+        .into_iter()
+        .find(|variant| *variant as i32 == stage)
+    }
+}
+
+/// A clip's parameters resolved against the configuration
+struct ClipJob {
+    uuid: Uuid,
+    user_id: Option<UserId>,
+    channel: String,
+    /// Unix timestamps of the start and end of the clip
+    timeframe: [usize; 2],
+    quality: Quality,
+    profile: EncodeProfile,
+    output_format: OutputFormat,
+    /// Whether the result is uploaded to storage; otherwise it's served from the temp
+    /// directory until `[workers] retention_hours` have passed
+    upload: bool,
+}
+impl ClipJob {
+    /// Restore the parameters of a job stored in the database
+    fn from_recording(recording: &Recording) -> Result<Self> {
+        Ok(Self {
+            uuid: recording.uuid.clone(),
+            user_id: recording.user_id,
+            channel: recording.channel.clone(),
+            timeframe: [recording.rec_start, recording.rec_end]
+                .map(|bound| bound.and_utc().timestamp() as usize),
+            quality: recording.quality.parse()?,
+            profile: CONFIG.profile(&recording.encode_profile)?.clone(),
+            output_format: OutputFormat::from_extension(&recording.output_format)
+                .with_context(|| anyhow!("unknown output format {}", recording.output_format))?,
+            upload: recording.uploaded,
+        })
+    }
+    /// Length of the clip in seconds
+    fn duration(&self) -> f64 {
+        (self.timeframe[1] - self.timeframe[0]) as f64
+    }
+    fn priority(&self) -> Priority {
+        let short_clip_seconds = CONFIG.workers.short_clip_seconds;
+        if short_clip_seconds > 0 && self.timeframe[1] - self.timeframe[0] <= short_clip_seconds {
+            Priority::High
+        } else {
+            Priority::Normal
+        }
+    }
 }
 
-/// Convert the timestamp to a segment index (referred to in the digest as $Number$)
-fn calculate_segment_idx(timestamp: usize) -> usize {
-    // <SegmentTemplate ... timescale="50" duration="192" />
-    ((timestamp as f64) / (192. / 50.)).floor() as usize
+/// A representation to download along with the segment indices covering the clip
+struct Track {
+    representation: Representation,
+    segment_idx_bounds: [usize; 2],
+    /// Seconds between the start of the first segment and the requested start
+    offset: f64,
+}
+impl Track {
+    fn new(representation: &Representation, timeframe: [usize; 2]) -> Self {
+        let segment_idx_bounds = timeframe.map(|bound| representation.segment_idx(bound));
+        Self {
+            offset: timeframe[0] as f64 - representation.segment_start(segment_idx_bounds[0]),
+            segment_idx_bounds,
+            representation: representation.clone(),
+        }
+    }
+    /// Name used for this track's files
+    fn name(&self) -> &'static str {
+        self.representation.content_type.name()
+    }
+    fn segment_count(&self) -> usize {
+        self.segment_idx_bounds[1] - self.segment_idx_bounds[0] + 1
+    }
+}
+
+/// Path to a clip's result in the temp directory
+pub fn output_path(uuid: &str, extension: &str) -> PathBuf {
+    PathBuf::new()
+        .join(TEMP_DIRECTORY)
+        .join(uuid)
+        .join(format!("output.{extension}"))
+}
+
+/// Path to the initialization segment of a representation, shared between jobs
+pub fn init_segment_path(channel: &str, representation: &Representation) -> PathBuf {
+    PathBuf::new()
+        .join(TEMP_DIRECTORY)
+        .join(INIT_DIRECTORY)
+        .join(channel)
+        .join(format!("{}.m4s", representation_file_name(representation)))
+}
+
+/// Key of a media segment in the segment cache
+fn segment_cache_key(
+    channel: &str,
+    representation: &Representation,
+    segment_idx: usize,
+) -> PathBuf {
+    PathBuf::from(channel)
+        .join(representation_file_name(representation))
+        .join(format!("{segment_idx}.m4s"))
+}
+
+/// Representation ID usable in a file name
+pub fn representation_file_name(representation: &Representation) -> String {
+    representation
+        .id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
 }
 
 pub async fn ffmpeg_progress_update_handler(
@@ -175,134 +675,224 @@ pub async fn ffmpeg_progress_update_handler(
     Ok(warp::reply())
 }
 
-async fn wait_in_queue(status_reporter: &mut StatusReporter, uuid: Uuid) -> Result<()> {
-    let (tx, mut rx) = unbounded_channel();
-    let len = {
-        let mut queue_channels = JOB_QUEUE_CHANNEL.write().await;
-        queue_channels.push_back((uuid.clone(), tx));
-        queue_channels.len()
-    };
-    let queue_pos = len - 1;
-    status_reporter
-        .update(
-            format!("Queue position: {queue_pos}"),
-            ShortStatus::Some(format!("#{queue_pos}")),
-            Stage::WaitingQueue,
-        )
-        .await?;
-    if len > MAX_JOB_COUNT {
-        loop {
-            if let Some(JobQueueReport::InQueue(queue_pos)) = rx.recv().await {
-                info!("{uuid}: waiting in queue pos {queue_pos}");
-                status_reporter
-                    .update(
-                        format!("Queue position: {queue_pos}"),
-                        ShortStatus::Some(format!("#{queue_pos}")),
-                        Stage::WaitingQueue,
-                    )
-                    .await?;
-                continue;
-            }
-            break;
-        }
+/// Join the queue, returning the channel reporting the job's position
+async fn join_queue(job: &ClipJob) -> UnboundedReceiver<JobQueueReport> {
+    CANCELLATION_TOKENS
+        .lock()
+        .await
+        .insert(job.uuid.clone(), CancellationToken::new());
+    let (tx, rx) = unbounded_channel();
+    JOB_QUEUE.lock().await.join(
+        job.uuid.clone(),
+        job.user_id,
+        job.priority(),
+        tx,
+        CONFIG.workers.jobs,
+    );
+    rx
+}
+async fn wait_in_queue(
+    status_reporter: &mut StatusReporter,
+    uuid: &str,
+    mut rx: UnboundedReceiver<JobQueueReport>,
+) -> Result<()> {
+    while let JobQueueReport::InQueue(queue_pos) = rx.recv().await.context("queue went away")? {
+        info!("{uuid}: waiting in queue pos {queue_pos}");
+        status_reporter
+            .update(
+                format!("Queue position: {queue_pos}"),
+                ShortStatus::Some(format!("#{queue_pos}")),
+                Stage::WaitingQueue,
+            )
+            .await?;
     }
     debug!("{uuid}: queue ready!");
     Ok(())
 }
-async fn advance_queue(pop_uuid: Uuid) -> Result<()> {
+/// Cancel a queued or running clip, returning whether there was one
+pub async fn cancel_clip(uuid: &str) -> bool {
+    match CANCELLATION_TOKENS.lock().await.get(uuid) {
+        Some(token) => {
+            info!("{uuid}: cancelling");
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+async fn advance_queue(pop_uuid: &str) -> Result<()> {
     debug!("{pop_uuid}: advancing queue");
+    JOB_QUEUE.lock().await.leave(pop_uuid, CONFIG.workers.jobs)
+}
 
-    let mut queue_channels = JOB_QUEUE_CHANNEL.write().await;
-    let pop_idx = queue_channels
-        .iter()
-        .enumerate()
-        .find_map(|(idx, (uuid, _))| if uuid == &pop_uuid { Some(idx) } else { None })
-        .context(anyhow!("failed to pop uuid {pop_uuid}"))?;
-    queue_channels.remove(pop_idx);
-
-    // We just mutated the queue to remove ourself, so the index is subtracted by 1
-    for idx in (MAX_JOB_COUNT - 1)..queue_channels.len() {
-        debug!("{pop_uuid}: alerting queue pos {idx}");
-        let (_, update) = &queue_channels[idx];
-        update.send(if idx == (MAX_JOB_COUNT - 1) {
-            JobQueueReport::Ready
-        } else {
-            JobQueueReport::InQueue(idx)
-        })?;
+/// Fetch the source's manifest and pick the tracks covering the timeframe
+async fn initialize(
+    status_reporter: &mut StatusReporter,
+    channel: &str,
+    timeframe: [usize; 2],
+    quality: &Quality,
+) -> Result<Vec<Track>> {
+    status_reporter
+        .update(
+            "Fetching manifest".to_string(),
+            ShortStatus::Clear,
+            Stage::Initializing,
+        )
+        .await?;
+
+    let source = sources::get(channel).context("failed to find channel")?;
+    let manifest = source
+        .manifest()
+        .await
+        .context("failed to fetch manifest")?;
+
+    let tracks = manifest
+        .select(quality)?
+        .into_iter()
+        .map(|representation| Track::new(representation, timeframe))
+        .collect::<Vec<_>>();
+    for track in &tracks {
+        debug!(
+            "{channel}: using {} representation {} (segments {:?})",
+            track.name(),
+            track.representation.id,
+            track.segment_idx_bounds
+        );
     }
 
-    Ok(())
+    Ok(tracks)
 }
 
-/// Download a url to a path.
-/// If the file already exists, the url will not be downloaded.
-/// If the download fails, the path will be deleted.
-/// Returns whether or not the url was downloaded.
-async fn download(url: String, path: &Path) -> Result<bool> {
+/// Download a file to a path from the first of `urls` that serves it, healthiest origin first,
+/// going through the list again with exponential backoff until `[download] retries` are used up.
+/// If the file already exists, it will not be downloaded.
+/// The file is written next to the path with a `.part` suffix and only moved into place
+/// once complete, so an interrupted download is never mistaken for a finished one.
+/// Returns whether or not the file was downloaded.
+pub async fn download(urls: &[String], path: &Path) -> Result<bool> {
     if path.exists() {
         return Ok(false);
     }
-
-    if let Some(e) = async move {
-        trace!("downloading {url} to {path}", path = path.display());
-        let start = Instant::now();
-        let client = reqwest::ClientBuilder::new()
-            .connect_timeout(TimeDelta::seconds(10).to_std()?)
-            .build()?;
-        let mut resp = client
-            .execute(client.get(&url).build()?)
-            .await
-            .and_then(|resp| resp.error_for_status())
-            .with_context(|| anyhow!("request to {url}"))?;
-        let mut output = File::create(&path)
-            .await
-            .with_context(|| anyhow!("creating file {path}", path = path.display()))?;
-        let mut chunk_idx = 0;
-        // todo: timeout :)
-        while let Some(chunk) = resp.chunk().await? {
-            trace!("{url} chunk {chunk_idx}");
-            chunk_idx += 1;
-            output.write(&chunk).await.with_context(|| {
-                anyhow!("writing chunk from {url} -> {path}", path = path.display())
-            })?;
-        }
-        trace!("{url} done!");
-        Ok::<_, anyhow::Error>(())
+    if urls.is_empty() {
+        bail!("no URLs to download {path} from", path = path.display());
     }
-    .await
-    .err()
-    {
-        let _ = remove_file(path).await;
-        Err(e)?;
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    let config = &CONFIG.download;
+    let mut delay = Duration::from_millis(config.retry_delay_ms);
+    for attempt in 0..=config.retries {
+        let ranked = health::rank(urls);
+        for (idx, url) in ranked.iter().enumerate() {
+            let start = Instant::now();
+            let result = download_once(url, path, &part_path).await;
+            health::record(url, result.is_ok(), start.elapsed());
+            match result {
+                Ok(()) => return Ok(true),
+                Err(e) => {
+                    let _ = remove_file(&part_path).await;
+                    if attempt == config.retries && idx == ranked.len() - 1 {
+                        return Err(e);
+                    }
+                    warn!(
+                        "download attempt {attempt} of {path} failed: {e:?}",
+                        attempt = attempt + 1,
+                        path = path.display()
+                    );
+                }
+            }
+        }
+        sleep(delay).await;
+        delay *= 2;
     }
 
-    Ok(true)
+    unreachable!("the last attempt returns")
 }
 
-async fn download_init_segments(channel: &str, url_prefix: &str) -> Result<()> {
-    let video_url = format!("{url_prefix}v=pv14/b=5070016/segment.init");
-    let audio_url = format!("{url_prefix}a=pa3/al=en-GB/ap=main/b=96000/segment.init");
-
-    let init_path = PathBuf::new()
-        .join(TEMP_DIRECTORY)
-        .join(INIT_DIRECTORY)
-        .join(channel);
-    create_dir_all(&init_path).await?;
-    let video_path = init_path.join("video_init.m4s");
-    let audio_path = init_path.join("audio_init.m4s");
+/// Make a single attempt at downloading `url` to `path` through `part_path`
+async fn download_once(url: &str, path: &Path, part_path: &Path) -> Result<()> {
+    trace!("downloading {url} to {path}", path = path.display());
+    let start = Instant::now();
+    let mut resp = DOWNLOAD_CLIENT
+        .get(url)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .with_context(|| anyhow!("request to {url}"))?;
+    let mut output = File::create(part_path)
+        .await
+        .with_context(|| anyhow!("creating file {path}", path = part_path.display()))?;
+    let mut chunk_idx = 0;
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .with_context(|| anyhow!("reading from {url}"))?
+    {
+        trace!("{url} chunk {chunk_idx}");
+        chunk_idx += 1;
+        output.write_all(&chunk).await.with_context(|| {
+            anyhow!(
+                "writing chunk from {url} -> {path}",
+                path = part_path.display()
+            )
+        })?;
+    }
+    output.sync_data().await?;
+    tokio::fs::rename(part_path, path)
+        .await
+        .with_context(|| anyhow!("moving {path} into place", path = part_path.display()))?;
+    trace!(
+        "{url} done in {ms}ms!",
+        ms = Instant::now().duration_since(start).as_millis()
+    );
+    Ok(())
+}
 
-    download(video_url, &video_path).await?;
-    download(audio_url, &audio_path).await?;
+async fn download_init_segments(
+    channel: &str,
+    url_prefixes: &[String],
+    tracks: &[Track],
+) -> Result<()> {
+    for Track { representation, .. } in tracks {
+        download_init_segment(channel, url_prefixes, representation).await?;
+    }
 
     Ok(())
 }
 
+/// Download the initialization segment of a representation unless it's already there.
+/// Jobs and the DVR share the file, so only one of them downloads it at a time.
+pub async fn download_init_segment(
+    channel: &str,
+    url_prefixes: &[String],
+    representation: &Representation,
+) -> Result<PathBuf> {
+    let path = init_segment_path(channel, representation);
+    if path.exists() {
+        return Ok(path);
+    }
+    let urls = url_prefixes
+        .iter()
+        .map(|url_prefix| representation.init_url(url_prefix))
+        .collect::<Result<Vec<_>>>()?;
+    segment_cache::exclusive(path.clone(), || async {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await?;
+        }
+        download(&urls, &path).await
+    })
+    .await?;
+    Ok(path)
+}
+
 /// Download segments to the resultant directory
 async fn download_segments(
     status_reporter: &mut StatusReporter,
     uuid: &str,
     channel: &str,
-    segment_idx_bounds: [usize; 2],
+    tracks: &[Track],
     target_directory: &Path,
 ) -> Result<()> {
     status_reporter
@@ -313,56 +903,68 @@ async fn download_segments(
         )
         .await?;
 
-    let &SourceEntry { url_prefix, .. } = SOURCES.get(channel).context("failed to find channel")?;
-    download_init_segments(channel, url_prefix)
+    let source = sources::get(channel).context("failed to find channel")?;
+    let url_prefixes = &source.url_prefixes;
+    download_init_segments(channel, url_prefixes, tracks)
         .await
         .context("failed to download initial segments")?;
 
+    // Segments of each track are downloaded together so tracks progress at the same rate
+    let total = tracks.iter().map(Track::segment_count).max().unwrap_or(0);
     let status_reporter = Arc::new(Mutex::new(status_reporter));
     let download_count = Arc::new(AtomicUsize::new(0));
-    stream::iter(segment_idx_bounds[0]..=segment_idx_bounds[1])
-        .map(|segment_idx| Ok::<_, anyhow::Error>(segment_idx))
-        .try_for_each_concurrent(VIDEO_DOWNLOAD_JOB_COUNT, |segment_idx| {
+    stream::iter(0..total)
+        .map(Ok::<_, anyhow::Error>)
+        .try_for_each_concurrent(CONFIG.workers.downloads_per_job, |offset| {
             let status_reporter = status_reporter.clone();
             let download_count = download_count.clone();
             async move {
                 let start = Instant::now();
-                let progress = format!(
-                    "{}/{}",
-                    segment_idx - segment_idx_bounds[0] + 1,
-                    segment_idx_bounds[1] - segment_idx_bounds[0] + 1
-                );
-                debug!("{uuid}: downloading segment {progress}");
-
-                let video_url = format!("{url_prefix}t=3840/v=pv14/b=5070016/{segment_idx}.m4s");
-                let audio_url =
-                    format!("{url_prefix}t=3840/a=pa3/al=en-GB/ap=main/b=96000/{segment_idx}.m4s");
+                debug!("{uuid}: downloading segment {}/{total}", offset + 1);
 
                 let base_path = PathBuf::from(target_directory);
-                let video_path = base_path.join(format!("video_{segment_idx}.m4s"));
-                let audio_path = base_path.join(format!("audio_{segment_idx}.m4s"));
-
-                if let Some(e) = <(_, _) as Into<[Result<_>; 2]>>::into(join!(
-                    download(video_url, &video_path),
-                    download(audio_url, &audio_path)
-                ))
-                .into_iter()
-                .find_map(|result| result.err())
-                {
-                    Err(e)?;
-                }
+                let downloads = tracks
+                    .iter()
+                    .filter(|track| offset < track.segment_count())
+                    .map(|track| {
+                        let segment_idx = track.segment_idx_bounds[0] + offset;
+                        let urls = url_prefixes
+                            .iter()
+                            .map(|url_prefix| {
+                                track.representation.segment_url(url_prefix, segment_idx)
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        let path =
+                            base_path.join(format!("{}_{segment_idx}.m4s", track.name()));
+                        let key = segment_cache_key(channel, &track.representation, segment_idx);
+                        let buffered =
+                            dvr::segment_path(channel, &track.representation, segment_idx);
+                        Ok((urls, key, buffered, path))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                try_join_all(downloads.into_iter().map(|(urls, key, buffered, path)| async move {
+                    if path.exists() {
+                        return Ok(false);
+                    }
+                    // Recorded channels may have the segment from before the CDN's window
+                    if buffered.exists() && segment_cache::link(&buffered, &path).await.is_ok() {
+                        return Ok(false);
+                    }
+                    segment_cache::fetch_into(key, &path, |cache_path| async move {
+                        download(&urls, &cache_path).await.map(|_| ())
+                    })
+                    .await
+                }))
+                .await?;
 
                 let duration_sec = Instant::now().duration_since(start).as_secs();
                 let count = download_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
-                let progress = format!(
-                    "{count}/{}",
-                    segment_idx_bounds[1] - segment_idx_bounds[0] + 1
-                );
+                let progress = format!("{count}/{total}");
                 let mut status_reporter = status_reporter.lock().await;
                 status_reporter
                     .update(
                         format!("Downloaded {progress}  total segments (last finished: {progress} in {duration_sec} sec)"),
-                        ShortStatus::Some(format!("{progress}")),
+                        ShortStatus::Some(progress),
                         Stage::Downloading,
                     )
                     .await?;
@@ -375,146 +977,341 @@ async fn download_segments(
     Ok(())
 }
 
+/// Get an FFmpeg builder with default options
+fn ffmpeg_builder<'a>() -> FfmpegBuilder<'a> {
+    FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .option(Parameter::Single("nostdin"))
+        .option(Parameter::Single("y")) // overwrite output files
+}
+
+/// Run an FFmpeg job to completion
+async fn run_ffmpeg(builder: FfmpegBuilder<'_>, description: &str) -> Result<()> {
+    let output = Command::from(builder.to_command())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| anyhow!("spawning {description} job"))?
+        .wait_with_output()
+        .await?;
+    if !output.status.success() {
+        Err(anyhow!(
+            "{description} job failed! {e}",
+            e = std::str::from_utf8(&output.stderr)?
+        ))?;
+    }
+    Ok(())
+}
+
+/// FFmpeg output options re-encoding the boundaries of smart-cut video
+const TRIM_ENCODE_OPTIONS: &[(&str, &str)] =
+    &[("c:v", "libx264"), ("crf", "18"), ("preset", "veryfast")];
+
+/// Whether a clip keeps its video as is, re-encoding only the boundary segments to trim
+/// precisely; everything else is trimmed while decoding in the combine job
+fn smart_cuts(profile: &EncodeProfile, output_format: OutputFormat) -> bool {
+    !output_format.is_audio_only() && !profile.encodes_video()
+}
+
+/// The FFmpeg output options a clip is produced with, as stored on its row
+fn describe_encode_options(profile: &EncodeProfile, output_format: OutputFormat) -> String {
+    fn join_options<'a>(options: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+        options
+            .into_iter()
+            .map(|(key, value)| format!("-{key} {value}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    if output_format.is_audio_only() {
+        return format!(
+            "{} -vn",
+            join_options(output_format.audio_options().iter().copied())
+        );
+    }
+    let profile_options = profile.output_options();
+    let options = join_options(
+        profile_options
+            .iter()
+            .map(|(key, value)| (*key, value.as_str())),
+    );
+    if smart_cuts(profile, output_format) {
+        format!(
+            "{options}; boundaries: {}",
+            join_options(TRIM_ENCODE_OPTIONS.iter().copied())
+        )
+    } else {
+        options
+    }
+}
+
+/// Cut a concatenated video track to `duration` seconds from the track's offset without
+/// re-encoding all of it. Segments start on keyframes, so only the partial segments at
+/// either end are re-encoded and the whole segments in between are copied.
+async fn trim_video(
+    job_path: &Path,
+    concat_path: &Path,
+    track: &Track,
+    duration: f64,
+) -> Result<PathBuf> {
+    /// Pieces shorter than this are dropped
+    const EPSILON: f64 = 0.001;
+
+    let segment_duration = track.representation.segment_duration();
+    let (start, end) = (track.offset, track.offset + duration);
+    // Keyframe-aligned bounds of the part that can be copied
+    let copy_start = (start / segment_duration - EPSILON).ceil() * segment_duration;
+    let copy_end = (end / segment_duration + EPSILON).floor() * segment_duration;
+    let pieces = if copy_end - copy_start < EPSILON {
+        vec![(start, end, true)]
+    } else {
+        vec![
+            (start, copy_start, true),
+            (copy_start, copy_end, false),
+            (copy_end, end, true),
+        ]
+    };
+
+    let mut piece_names = vec![];
+    for (from, to, encode) in pieces
+        .into_iter()
+        .filter(|(from, to, _)| to - from > EPSILON)
+    {
+        let name = format!("video_piece_{}.ts", piece_names.len());
+        let piece_path = job_path.join(&name);
+        trace!("{name}: {from:.3}..{to:.3} (encode: {encode})");
+
+        // Seeking a hair past a keyframe lands on it rather than the one before it
+        let seek = format!("{:.3}", if encode { from } else { from + EPSILON });
+        let length = format!("{:.3}", to - from);
+        let mut output = ffmpeg_cli::File::new(piece_path.to_str().unwrap())
+            .option(Parameter::KeyValue("t", &length))
+            .option(Parameter::Single("an"));
+        output = if encode {
+            TRIM_ENCODE_OPTIONS
+                .iter()
+                .fold(output, |output, &(key, value)| {
+                    output.option(Parameter::KeyValue(key, value))
+                })
+        } else {
+            output.option(Parameter::KeyValue("c:v", "copy"))
+        };
+        run_ffmpeg(
+            ffmpeg_builder()
+                .input(
+                    ffmpeg_cli::File::new(concat_path.to_str().unwrap())
+                        .option(Parameter::KeyValue("ss", &seek)),
+                )
+                .output(output),
+            "video trim",
+        )
+        .await?;
+        piece_names.push(name);
+    }
+
+    // The concat demuxer resolves paths relative to the list
+    let list_path = job_path.join("video_pieces.txt");
+    tokio::fs::write(
+        &list_path,
+        piece_names
+            .iter()
+            .map(|name| format!("file '{name}'\n"))
+            .collect::<String>(),
+    )
+    .await?;
+    let trimmed_path = job_path.join("video_trimmed.mp4");
+    run_ffmpeg(
+        ffmpeg_builder()
+            .input(
+                ffmpeg_cli::File::new(list_path.to_str().unwrap())
+                    .option(Parameter::KeyValue("f", "concat"))
+                    .option(Parameter::KeyValue("safe", "0")),
+            )
+            .output(
+                ffmpeg_cli::File::new(trimmed_path.to_str().unwrap())
+                    .option(Parameter::KeyValue("c", "copy")),
+            ),
+        "video piece concat",
+    )
+    .await?;
+
+    Ok(trimmed_path)
+}
+
 async fn combine_segments(
     status_reporter: &mut StatusReporter,
-    segment_idx_bounds: [usize; 2],
-    uuid: &str,
-    channel: &str,
-    encode: bool,
+    job: &ClipJob,
+    tracks: &[Track],
+    reuse_concatenated: bool,
     ffmpeg_progress_channels: FfmpegProgressChannels,
 ) -> Result<()> {
+    let ClipJob {
+        uuid,
+        channel,
+        profile,
+        output_format,
+        ..
+    } = job;
+    let duration = job.duration();
     status_reporter
         .update(
-            format!("Starting segment combination"),
+            "Starting segment combination".to_string(),
             ShortStatus::Clear,
             Stage::Combining,
         )
         .await?;
 
-    // Helper to get an FFmpeg builder with default options
-    let get_default_builder = || {
-        FfmpegBuilder::new()
-            .stderr(Stdio::piped())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .option(Parameter::Single("nostdin"))
-            .option(Parameter::Single("y")) // overwrite output files
-    };
-
     // Create progress channel
     let (tx, mut progress_rx) = unbounded_channel();
     {
         let mut lookup = ffmpeg_progress_channels.write().await;
-        lookup.insert(uuid.to_string(), tx);
+        lookup.insert(uuid.clone(), tx);
     }
 
     let job_path = PathBuf::new().join(TEMP_DIRECTORY).join(uuid);
-    let init_path = PathBuf::new()
-        .join(TEMP_DIRECTORY)
-        .join(INIT_DIRECTORY)
-        .join(channel);
-
-    let output_path = job_path.join("output.mp4");
-    let video_concat_path = job_path.join("video_full.mp4");
-    let audio_concat_path = job_path.join("audio_full.mp4");
-
-    // Get concatenation inputs (`-i "concat:{init}|{segment 1}...|{segment n}"`)
-    let [video_concat_input, audio_concat_input] = ["video", "audio"].map(|r#type| {
-        let init = init_path.join(&format!("{type}_init.m4s"));
-        let mut res = format!("concat:{}", init.to_string_lossy().to_string());
-        for path in (segment_idx_bounds[0]..=segment_idx_bounds[1])
-            .map(|segment_idx| job_path.join(&format!("{type}_{segment_idx}.m4s")))
-        {
-            res.push_str(&format!("|{}", path.to_string_lossy()));
-        }
-        res
-    });
+    let progress_url = format!("http://127.0.0.1:{PORT}/ffmpeg-progress/{uuid}");
 
-    // Video and audio concatenation
-    let [video_concat, audio_concat] = [
-        (&video_concat_input, video_concat_path.to_str().unwrap()),
-        (&audio_concat_input, audio_concat_path.to_str().unwrap()),
-    ]
-    .map(|(input, output)| {
-        get_default_builder()
-            .input(ffmpeg_cli::File::new(input))
-            .output(ffmpeg_cli::File::new(output).option(Parameter::KeyValue("c", "copy")))
-    });
+    let output_path = output_path(uuid, output_format.extension());
+    let concat_paths = tracks
+        .iter()
+        .map(|track| job_path.join(format!("{}_full.mp4", track.name())))
+        .collect::<Vec<_>>();
 
-    let video_concat_job = Command::from(
-        video_concat
-            .option(Parameter::KeyValue(
-                "progress",
-                &format!("http://127.0.0.1:{PORT}/ffmpeg-progress/{uuid}"),
-            ))
-            .to_command(),
-    )
-    .spawn()
-    .context("spawning video concat command")?;
-    let audio_concat_job = Command::from(audio_concat.to_command())
-        .spawn()
-        .context("spawning audio concat command")?;
+    if reuse_concatenated && concat_paths.iter().all(|path| path.exists()) {
+        debug!("{uuid}: reusing concatenated tracks");
+    } else {
+        // Get concatenation inputs (`-i "concat:{init}|{segment 1}...|{segment n}"`)
+        let concat_inputs = tracks
+            .iter()
+            .map(|track| {
+                let init = init_segment_path(channel, &track.representation);
+                let mut res = format!("concat:{}", init.to_string_lossy());
+                for path in (track.segment_idx_bounds[0]..=track.segment_idx_bounds[1])
+                    .map(|segment_idx| job_path.join(format!("{}_{segment_idx}.m4s", track.name())))
+                {
+                    res.push_str(&format!("|{}", path.to_string_lossy()));
+                }
+                res
+            })
+            .collect::<Vec<_>>();
 
-    status_reporter
-        .update(
-            format!("Concatenating audio and video segments"),
-            ShortStatus::Some("Concatenating".to_string()),
-            Stage::Combining,
-        )
-        .await?;
+        // Track concatenation; the first track reports progress so its duration is known
+        let mut concat_jobs = vec![];
+        for (idx, (track, (input, output))) in tracks
+            .iter()
+            .zip(concat_inputs.iter().zip(&concat_paths))
+            .enumerate()
+        {
+            let mut concat = ffmpeg_builder().input(ffmpeg_cli::File::new(input)).output(
+                ffmpeg_cli::File::new(output.to_str().unwrap())
+                    .option(Parameter::KeyValue("c", "copy")),
+            );
+            if idx == 0 {
+                concat = concat.option(Parameter::KeyValue("progress", &progress_url));
+            }
+            concat_jobs.push(
+                Command::from(concat.to_command())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| anyhow!("spawning {} concat command", track.name()))?,
+            );
+        }
 
-    let outputs = [
-        video_concat_job.wait_with_output().await?,
-        audio_concat_job.wait_with_output().await?,
-    ];
-    for output in outputs {
-        if !output.status.success() {
-            Err(anyhow!(
-                "concat job failed! {e}",
-                e = std::str::from_utf8(&output.stderr)?
-            ))?;
+        status_reporter
+            .update(
+                format!(
+                    "Concatenating {} segments",
+                    tracks
+                        .iter()
+                        .map(Track::name)
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                ),
+                ShortStatus::Some("Concatenating".to_string()),
+                Stage::Combining,
+            )
+            .await?;
+
+        for concat_job in concat_jobs {
+            let output = concat_job.wait_with_output().await?;
+            if !output.status.success() {
+                Err(anyhow!(
+                    "concat job failed! {e}",
+                    e = std::str::from_utf8(&output.stderr)?
+                ))?;
+            }
         }
     }
-    let mut time = None;
+    // The output is trimmed to the requested timeframe, so its length is already known
+    let time = Some(TimeDelta::milliseconds((duration * 1000.) as i64));
     // If FFmpeg exits "too quickly," then it won't send a progress report and the Rx will be empty
-    while progress_rx.len() > 0 {
-        if let Some(Some(progress)) = progress_rx.recv().await {
-            time = Some(progress);
+    while !progress_rx.is_empty() {
+        progress_rx.recv().await;
+    }
+
+    let smart_cut = smart_cuts(profile, *output_format);
+    let mut inputs = vec![];
+    for (track, concat_path) in tracks.iter().zip(&concat_paths) {
+        if smart_cut && track.representation.content_type == ContentType::Video {
+            status_reporter
+                .update(
+                    "Trimming video to the requested timeframe".to_string(),
+                    ShortStatus::Some("Trimming".to_string()),
+                    Stage::Combining,
+                )
+                .await?;
+            let trimmed_path = trim_video(&job_path, concat_path, track, duration).await?;
+            inputs.push((trimmed_path, None));
+        } else {
+            inputs.push((concat_path.clone(), Some(format!("{:.3}", track.offset))));
         }
     }
+    let length = format!("{duration:.3}");
 
-    // Combine or encode concatenated audio and video
+    // Combine or encode concatenated tracks
     status_reporter
         .update(
-            format!("Combining and encoding segments"),
+            "Combining and encoding segments".to_string(),
             ShortStatus::Clear,
             Stage::Encoding,
         )
         .await?;
-    let mut combine_job = get_default_builder()
-        .input(ffmpeg_cli::File::new(audio_concat_path.to_str().unwrap()))
-        .input(ffmpeg_cli::File::new(video_concat_path.to_str().unwrap()))
-        .output(
-            ffmpeg_cli::File::new(output_path.to_str().unwrap())
-                .option(Parameter::KeyValue("c:a", "copy"))
-                .option(Parameter::KeyValue(
-                    "c:v",
-                    if encode { "libx264" } else { "copy" },
-                )),
-        )
-        .option(Parameter::KeyValue(
-            "progress",
-            &format!("http://127.0.0.1:{PORT}/ffmpeg-progress/{uuid}"),
-        ))
-        .to_command()
-        .spawn()
-        .context("spawning combine job")?;
+    let mut combine = ffmpeg_builder();
+    for (path, seek) in &inputs {
+        let mut input = ffmpeg_cli::File::new(path.to_str().unwrap());
+        if let Some(seek) = seek {
+            input = input.option(Parameter::KeyValue("ss", seek));
+        }
+        combine = combine.input(input);
+    }
+    let mut output = ffmpeg_cli::File::new(output_path.to_str().unwrap())
+        .option(Parameter::KeyValue("t", &length));
+    let profile_options = profile.output_options();
+    if output_format.is_audio_only() {
+        for &(key, value) in output_format.audio_options() {
+            output = output.option(Parameter::KeyValue(key, value));
+        }
+        output = output.option(Parameter::Single("vn"));
+    } else {
+        for (key, value) in &profile_options {
+            output = output.option(Parameter::KeyValue(key, value));
+        }
+    }
+    let mut combine_job = Command::from(
+        combine
+            .output(output)
+            .option(Parameter::KeyValue("progress", &progress_url))
+            .to_command(),
+    )
+    .kill_on_drop(true)
+    .spawn()
+    .context("spawning combine job")?;
     sleep(TimeDelta::seconds(1).to_std()?).await;
     if let Some(status) = combine_job.try_wait()? {
         if !status.success() {
             return Err(anyhow!(
                 "combine job failed! {}",
-                std::str::from_utf8(&combine_job.wait_with_output()?.stderr)?
+                std::str::from_utf8(&combine_job.wait_with_output().await?.stderr)?
             ));
         }
     }
@@ -544,7 +1341,7 @@ async fn combine_segments(
             break;
         }
     }
-    let output = combine_job.wait_with_output()?;
+    let output = combine_job.wait_with_output().await?;
     if !output.status.success() {
         Err(anyhow!(
             "combine job failed! {}",
@@ -555,7 +1352,12 @@ async fn combine_segments(
     Ok(())
 }
 
-async fn upload(status_reporter: &mut StatusReporter, uuid: &str) -> Result<()> {
+async fn upload(
+    status_reporter: &mut StatusReporter,
+    storage: &Storage,
+    uuid: &str,
+    output_format: OutputFormat,
+) -> Result<()> {
     status_reporter
         .update(
             "Uploading result".to_string(),
@@ -564,46 +1366,78 @@ async fn upload(status_reporter: &mut StatusReporter, uuid: &str) -> Result<()>
         )
         .await?;
 
-    let output_path = PathBuf::new()
-        .join(TEMP_DIRECTORY)
-        .join(uuid)
-        .join("output.mp4");
-
-    Command::new("curl")
-        .args([
-            "-T",
-            output_path.to_str().unwrap(),
-            "-u",
-            &format!(
-                "{WEBDAV_USERNAME}:{WEBDAV_PASSWORD}",
-                WEBDAV_USERNAME = WEBDAV_USERNAME.to_string(),
-                WEBDAV_PASSWORD = WEBDAV_PASSWORD.to_string()
-            ),
-            &format!(
-                "{WEBDAV_URL}/bbcd/{uuid}.mp4",
-                WEBDAV_URL = WEBDAV_URL.to_string()
-            ),
-        ])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?
-        .wait_with_output()
-        .await?;
+    let output_path = output_path(uuid, output_format.extension());
+
+    let size = tokio::fs::metadata(&output_path).await?.len();
+    let key = clip_key(uuid, output_format.extension());
+    let (tx, mut progress_rx) = unbounded_channel();
+    let upload = upload_file(storage, &output_path, &key, &CONFIG.upload, tx);
+    tokio::pin!(upload);
+    loop {
+        tokio::select! {
+            result = &mut upload => {
+                result.context("failed to upload result")?;
+                break;
+            }
+            Some(sent) = progress_rx.recv() => {
+                let percentage = (sent as f32 / size.max(1) as f32) * 100.;
+                status_reporter
+                    .update(
+                        format!("({percentage:.2}%)"),
+                        ShortStatus::Some(format!("{percentage:.0}%")),
+                        Stage::Uploading,
+                    )
+                    .await?;
+            }
+        }
+    }
 
     Ok(())
 }
 
 pub async fn clip(
     uuid: String,
-    channel: String,
-    timeframe: [usize; 2],
-    encode: bool,
+    parameters: ClipParameters,
+    user: Option<User>,
     database: Database,
+    storage: Storage,
     clients: ClientConnections,
     ffmpeg_progress_channels: FfmpegProgressChannels,
 ) -> Result<()> {
     info!("{uuid}: starting clip");
 
+    let profile_name = parameters.profile_name().to_string();
+    let profile = CONFIG.profile(&profile_name)?.clone();
+    let ClipParameters {
+        start_timestamp,
+        end_timestamp,
+        channel,
+        quality,
+        audio_format,
+        ..
+    } = parameters;
+    let timeframe = [start_timestamp, end_timestamp];
+    let output_format = match quality {
+        Quality::AudioOnly => audio_format.unwrap_or_default().into(),
+        _ => profile.container,
+    };
+    let encode_options = describe_encode_options(&profile, output_format);
+    // Results of users who can't upload stay in the temp directory
+    let upload = user
+        .as_ref()
+        .is_none_or(|user| user.can(Permission::Upload));
+    let job = ClipJob {
+        uuid: uuid.clone(),
+        user_id: user.map(|user| user.id),
+        channel: channel.clone(),
+        timeframe,
+        quality,
+        profile,
+        output_format,
+        upload,
+    };
+    let quality = job.quality.to_string();
+
     let mut timestamp_bounds = timeframe.iter().map(|bound| -> Result<_> {
         Ok(DateTime::from_timestamp(*bound as i64, 0)
             .context("failed to convert bounds to timestamp")?
@@ -613,7 +1447,7 @@ pub async fn clip(
     let mut status_reporter = StatusReporter {
         clients,
         recording_row: RecordingUpdate {
-            user_id: None,
+            user_id: job.user_id,
             rec_start: timestamp_bounds.next().unwrap()?,
             rec_end: timestamp_bounds.next().unwrap()?,
             stage: 0,
@@ -621,6 +1455,11 @@ pub async fn clip(
             short_status: "".to_string(),
             uuid: uuid.clone(),
             channel: channel.clone(),
+            output_format: output_format.extension().to_string(),
+            encode_profile: profile_name,
+            encode_options,
+            quality,
+            uploaded: upload,
         },
         database,
     };
@@ -629,37 +1468,297 @@ pub async fn clip(
         .create_recording(&status_reporter.recording_row)?;
     status_reporter.alert(&recording).await?;
 
-    let output_directory = PathBuf::from(TEMP_DIRECTORY).join(&uuid);
-    let segment_idx_bounds = timeframe.map(|bound| calculate_segment_idx(bound));
+    let queue = join_queue(&job).await;
+    run(
+        job,
+        status_reporter,
+        queue,
+        Stage::WaitingQueue,
+        storage,
+        ffmpeg_progress_channels,
+    )
+    .await
+}
+
+/// Re-enqueue the recordings left unfinished by a previous run, in their original order.
+/// Jobs that were interrupted part-way resume with the segments already downloaded.
+pub async fn resume_clips(
+    pool: PoolPg,
+    storage: Storage,
+    clients: ClientConnections,
+    ffmpeg_progress_channels: FfmpegProgressChannels,
+) -> Result<()> {
+    let mut database = Database {
+        connection: pool.get()?,
+    };
+    let recordings = database.get_unfinished_recordings()?;
+    if !recordings.is_empty() {
+        info!(
+            "resuming {count} unfinished clips",
+            count = recordings.len()
+        );
+    }
+
+    // Jobs are queued one after another so the original order is kept
+    for recording in recordings {
+        let uuid = recording.uuid.clone();
+        // Interrupted jobs carry on from the stage they were in
+        let resume_from = Stage::from_row(recording.stage).unwrap_or(Stage::WaitingQueue);
+        let status_reporter = StatusReporter {
+            clients: clients.clone(),
+            database: Database {
+                connection: pool.get()?,
+            },
+            recording_row: recording.clone().into(),
+        };
+        let status = if matches!(resume_from, Stage::WaitingQueue) {
+            "Requeued after restart"
+        } else {
+            info!("{uuid}: interrupted by restart, resuming from {resume_from:?}");
+            "Interrupted by restart, resuming"
+        };
+        requeue(
+            recording,
+            resume_from,
+            status,
+            status_reporter,
+            storage.clone(),
+            ffmpeg_progress_channels.clone(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Queue a failed clip again, reusing the files kept from its last attempt
+pub async fn retry_clip(
+    recording: Recording,
+    database: Database,
+    storage: Storage,
+    clients: ClientConnections,
+    ffmpeg_progress_channels: FfmpegProgressChannels,
+) -> Result<()> {
+    let resume_from = Stage::from_row(recording.stage)
+        .filter(|stage| stage.is_failure())
+        .context("only failed clips can be retried")?
+        .retry_variant();
+    info!(
+        "{uuid}: retrying from {resume_from:?}",
+        uuid = recording.uuid
+    );
+    let status_reporter = StatusReporter {
+        clients,
+        database,
+        recording_row: recording.clone().into(),
+    };
+    requeue(
+        recording,
+        resume_from,
+        "Retrying",
+        status_reporter,
+        storage,
+        ffmpeg_progress_channels,
+    )
+    .await
+}
+
+/// Put a stored job back in the queue and run it once its turn comes
+async fn requeue(
+    recording: Recording,
+    resume_from: Stage,
+    status: &str,
+    mut status_reporter: StatusReporter,
+    storage: Storage,
+    ffmpeg_progress_channels: FfmpegProgressChannels,
+) -> Result<()> {
+    let uuid = recording.uuid.clone();
+    let job = match ClipJob::from_recording(&recording) {
+        Ok(job) => job,
+        Err(e) => {
+            error!("{uuid}: failed to requeue: {e:?}");
+            status_reporter
+                .update(
+                    format!("Failed to requeue: {e:?}"),
+                    ShortStatus::Clear,
+                    Stage::FailedNondescript,
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    status_reporter
+        .update(status.to_string(), ShortStatus::Clear, Stage::WaitingQueue)
+        .await?;
+
+    // Join the queue before spawning so the caller decides the order
+    let queue = join_queue(&job).await;
+    tokio::spawn(async move {
+        if let Err(e) = run(
+            job,
+            status_reporter,
+            queue,
+            resume_from,
+            storage,
+            ffmpeg_progress_channels,
+        )
+        .await
+        {
+            error!("{uuid}: requeued clip failed: {e:?}");
+        }
+    });
+
+    Ok(())
+}
+
+/// Remove what's left of a finished clip in the temp directory
+pub async fn remove_clip_files(uuid: &str) -> Result<()> {
+    let directory = PathBuf::from(TEMP_DIRECTORY).join(uuid);
+    if directory.exists() {
+        remove_dir_all(&directory)
+            .await
+            .with_context(|| anyhow!("removing {}", directory.display()))?;
+    }
+    Ok(())
+}
+
+/// Remove the files of clips that aren't queued or running once they are older than
+/// `[workers] retention_hours`, checking every hour
+pub async fn clean_temp_directory() {
+    let retention = std::time::Duration::from_secs(CONFIG.workers.retention_hours * 60 * 60);
+    loop {
+        if let Err(e) = async {
+            let mut entries = match tokio::fs::read_dir(TEMP_DIRECTORY).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => Err(e)?,
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name == INIT_DIRECTORY
+                    || name == segment_cache::DIRECTORY_NAME
+                    || name == dvr::DIRECTORY_NAME
+                    || CANCELLATION_TOKENS.lock().await.contains_key(&name)
+                {
+                    continue;
+                }
+                let age = entry
+                    .metadata()
+                    .await?
+                    .modified()?
+                    .elapsed()
+                    .unwrap_or_default();
+                if age > retention {
+                    info!(
+                        "{name}: removing files kept for {hours}h",
+                        hours = age.as_secs() / 3600
+                    );
+                    remove_dir_all(entry.path()).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await
+        {
+            error!("failed to clean the temp directory: {e:?}");
+        }
+        sleep(std::time::Duration::from_secs(60 * 60)).await;
+    }
+}
+
+/// Run a queued job to completion, recording the outcome in its row
+async fn run(
+    job: ClipJob,
+    mut status_reporter: StatusReporter,
+    queue: UnboundedReceiver<JobQueueReport>,
+    resume_from: Stage,
+    storage: Storage,
+    ffmpeg_progress_channels: FfmpegProgressChannels,
+) -> Result<()> {
+    let ClipJob {
+        uuid,
+        channel,
+        timeframe,
+        ..
+    } = &job;
+    let output_directory = PathBuf::from(TEMP_DIRECTORY).join(uuid);
+    let cancellation = CANCELLATION_TOKENS
+        .lock()
+        .await
+        .get(uuid)
+        .cloned()
+        .unwrap_or_default();
 
-    let result = async {
-        wait_in_queue(&mut status_reporter, uuid.to_string()).await?;
+    let work = async {
+        wait_in_queue(&mut status_reporter, uuid, queue).await?;
+        let tracks = initialize(&mut status_reporter, channel, *timeframe, &job.quality).await?;
         create_dir_all(&output_directory).await?;
         download_segments(
             &mut status_reporter,
-            &uuid,
-            &channel,
-            segment_idx_bounds,
+            uuid,
+            channel,
+            &tracks,
             &output_directory,
         )
         .await?;
-        combine_segments(
-            &mut status_reporter,
-            segment_idx_bounds,
-            &uuid,
-            &channel,
-            encode,
-            ffmpeg_progress_channels,
-        )
-        .await?;
-        upload(&mut status_reporter, &uuid).await?;
+        // A result that only failed to upload is complete
+        let output_path = output_path(uuid, job.output_format.extension());
+        if !matches!(resume_from, Stage::Uploading) || !output_path.exists() {
+            let _encode_permit = match ENCODE_SEMAPHORE.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    status_reporter
+                        .update(
+                            "Waiting for a free encoder".to_string(),
+                            ShortStatus::Clear,
+                            Stage::Combining,
+                        )
+                        .await?;
+                    ENCODE_SEMAPHORE.acquire().await?
+                }
+            };
+            combine_segments(
+                &mut status_reporter,
+                &job,
+                &tracks,
+                // Tracks are only encoded once they are fully concatenated
+                matches!(resume_from, Stage::Encoding | Stage::Uploading),
+                ffmpeg_progress_channels.clone(),
+            )
+            .await?;
+        }
+        if job.upload {
+            upload(&mut status_reporter, &storage, uuid, job.output_format).await?;
+        } else {
+            info!("{uuid}: keeping the result in the temp directory, the user can't upload");
+        }
 
         Ok::<_, anyhow::Error>(())
-    }
-    .await;
+    };
+    // Dropping the work stops downloads and kills any FFmpeg children
+    let result = tokio::select! {
+        result = work => Some(result),
+        _ = cancellation.cancelled() => None,
+    };
 
-    advance_queue(uuid.clone()).await?;
+    CANCELLATION_TOKENS.lock().await.remove(uuid);
+    ffmpeg_progress_channels.write().await.remove(uuid);
+    advance_queue(uuid).await?;
 
+    let Some(result) = result else {
+        status_reporter
+            .update(
+                "Cancelled".to_string(),
+                ShortStatus::Clear,
+                Stage::Cancelled,
+            )
+            .await?;
+        info!("{uuid}: cancelled, cleaning up");
+        if output_directory.exists() {
+            remove_dir_all(&output_directory).await?;
+        }
+        return Ok(());
+    };
     let e = match result {
         Ok(_) => {
             status_reporter
@@ -676,14 +1775,290 @@ pub async fn clip(
             format!("{e:?}"),
             ShortStatus::Clear,
             Stage::error_variant(unsafe {
-                std::mem::transmute(status_reporter.recording_row.stage as usize)
+                std::mem::transmute::<usize, Stage>(status_reporter.recording_row.stage as usize)
             }),
         )
         .await?;
 
     error!("{uuid}: failed: {e:?}");
-    error!("{uuid}: cleaning up");
+    info!("{uuid}: keeping files for a retry");
 
-    remove_dir_all(&output_directory).await?;
     Err(e)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    fn join(
+        queue: &mut JobQueue,
+        uuid: &str,
+        user_id: Option<UserId>,
+        priority: Priority,
+        workers: usize,
+    ) -> UnboundedReceiver<JobQueueReport> {
+        let (tx, rx) = unbounded_channel();
+        queue.join(uuid.to_string(), user_id, priority, tx, workers);
+        rx
+    }
+
+    /// Reports sent to a job since the last call; the queue lets go of started jobs
+    fn reports(rx: &mut UnboundedReceiver<JobQueueReport>) -> Vec<JobQueueReport> {
+        let mut reports = vec![];
+        loop {
+            match rx.try_recv() {
+                Ok(report) => reports.push(report),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return reports,
+            }
+        }
+    }
+
+    fn running(queue: &JobQueue) -> Vec<&str> {
+        let mut running = queue
+            .running
+            .iter()
+            .map(|(uuid, _)| uuid.as_str())
+            .collect::<Vec<_>>();
+        running.sort_unstable();
+        running
+    }
+
+    fn waiting(queue: &JobQueue) -> Vec<&str> {
+        queue
+            .scheduling_order()
+            .into_iter()
+            .map(|idx| queue.waiting[idx].uuid.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn records_the_options_ffmpeg_runs_with() {
+        let copy = EncodeProfile::default();
+        let x264 = EncodeProfile {
+            video_codec: "libx264".to_string(),
+            crf: Some(23),
+            ..Default::default()
+        };
+        assert_eq!(
+            describe_encode_options(&copy, OutputFormat::Mp4),
+            "-c:v copy -c:a copy; boundaries: -c:v libx264 -crf 18 -preset veryfast"
+        );
+        assert_eq!(
+            describe_encode_options(&x264, OutputFormat::Mkv),
+            "-c:v libx264 -crf 23 -c:a copy"
+        );
+        // Audio-only clips ignore the profile
+        assert_eq!(
+            describe_encode_options(&x264, OutputFormat::Mp3),
+            "-c:a libmp3lame -q:a 2 -vn"
+        );
+        assert_eq!(
+            describe_encode_options(&copy, OutputFormat::M4a),
+            "-c:a copy -vn"
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_without_urls_fail_at_once() {
+        let path = std::env::temp_dir().join(format!("{}.m4s", uuid::Uuid::new_v4()));
+        let result = tokio::time::timeout(Duration::from_secs(1), download(&[], &path)).await;
+        assert!(result.expect("waited through the retries").is_err());
+    }
+
+    #[test]
+    fn resumed_jobs_keep_skipping_the_upload() {
+        let mut recording = Recording {
+            id: 1,
+            user_id: Some(1),
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            rec_start: DateTime::from_timestamp(100, 0).unwrap().naive_utc(),
+            rec_end: DateTime::from_timestamp(130, 0).unwrap().naive_utc(),
+            status: String::new(),
+            short_status: String::new(),
+            stage: Stage::Uploading as i32,
+            channel: "bbc_one_hd".to_string(),
+            output_format: "mp4".to_string(),
+            encode_profile: COPY_PROFILE.to_string(),
+            encode_options: String::new(),
+            quality: "best".to_string(),
+            uploaded: false,
+        };
+        let job = ClipJob::from_recording(&recording).unwrap();
+        assert!(!job.upload);
+        assert_eq!(job.timeframe, [100, 130]);
+        recording.uploaded = true;
+        assert!(ClipJob::from_recording(&recording).unwrap().upload);
+    }
+
+    #[test]
+    fn audio_format_needs_audio_only_quality() {
+        let audio_format_errors = |body: serde_json::Value| {
+            let parameters: ClipParameters = serde_json::from_value(body).unwrap();
+            parameters
+                .validate(None, 60)
+                .into_iter()
+                .filter(|error| error.field == "audio_format")
+                .count()
+        };
+        let body = |extra: serde_json::Value| {
+            let mut body = serde_json::json!({
+                "start_timestamp": 100,
+                "end_timestamp": 130,
+                "channel": "tv1",
+            });
+            body.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            body
+        };
+        assert_eq!(audio_format_errors(body(serde_json::json!({}))), 0);
+        assert_eq!(
+            audio_format_errors(body(serde_json::json!({"quality": "audio-only"}))),
+            0
+        );
+        assert_eq!(
+            audio_format_errors(body(
+                serde_json::json!({"quality": "audio-only", "audio_format": "mp3"})
+            )),
+            0
+        );
+        assert_eq!(
+            audio_format_errors(body(serde_json::json!({"audio_format": "mp3"}))),
+            1
+        );
+        assert_eq!(
+            audio_format_errors(body(
+                serde_json::json!({"quality": "720p", "audio_format": "opus"})
+            )),
+            1
+        );
+    }
+
+    #[test]
+    fn single_worker_runs_jobs_in_arrival_order() {
+        let mut queue = JobQueue::default();
+        let mut a = join(&mut queue, "a", None, Priority::Normal, 1);
+        let mut b = join(&mut queue, "b", None, Priority::Normal, 1);
+        let mut c = join(&mut queue, "c", None, Priority::Normal, 1);
+        assert_eq!(reports(&mut a), [JobQueueReport::Ready]);
+        assert_eq!(reports(&mut b), [JobQueueReport::InQueue(1)]);
+        assert_eq!(reports(&mut c), [JobQueueReport::InQueue(2)]);
+
+        queue.leave("a", 1).unwrap();
+        assert_eq!(reports(&mut b), [JobQueueReport::Ready]);
+        assert_eq!(reports(&mut c), [JobQueueReport::InQueue(1)]);
+        assert_eq!(running(&queue), ["b"]);
+
+        queue.leave("b", 1).unwrap();
+        assert_eq!(reports(&mut c), [JobQueueReport::Ready]);
+        queue.leave("c", 1).unwrap();
+        assert!(queue.running.is_empty() && queue.waiting.is_empty());
+    }
+
+    #[test]
+    fn several_workers_share_slots() {
+        let mut queue = JobQueue::default();
+        let mut jobs =
+            ["a", "b", "c", "d", "e"].map(|uuid| join(&mut queue, uuid, None, Priority::Normal, 3));
+        for job in &mut jobs[..3] {
+            assert_eq!(reports(job), [JobQueueReport::Ready]);
+        }
+        assert_eq!(reports(&mut jobs[3]), [JobQueueReport::InQueue(1)]);
+        assert_eq!(reports(&mut jobs[4]), [JobQueueReport::InQueue(2)]);
+
+        // A waiting job leaving moves up the jobs behind it only
+        queue.leave("d", 3).unwrap();
+        assert_eq!(reports(&mut jobs[4]), [JobQueueReport::InQueue(1)]);
+        assert!(jobs[..3].iter_mut().all(|job| reports(job).is_empty()));
+
+        queue.leave("b", 3).unwrap();
+        assert_eq!(reports(&mut jobs[4]), [JobQueueReport::Ready]);
+        assert_eq!(running(&queue), ["a", "c", "e"]);
+        assert!(queue.leave("b", 3).is_err());
+    }
+
+    #[test]
+    fn unchanged_positions_are_not_reported_again() {
+        let mut queue = JobQueue::default();
+        let _a = join(&mut queue, "a", None, Priority::Normal, 1);
+        let mut b = join(&mut queue, "b", None, Priority::Normal, 1);
+        let mut c = join(&mut queue, "c", None, Priority::Normal, 1);
+        let _d = join(&mut queue, "d", None, Priority::Normal, 1);
+        assert_eq!(reports(&mut b), [JobQueueReport::InQueue(1)]);
+        assert_eq!(reports(&mut c), [JobQueueReport::InQueue(2)]);
+
+        queue.leave("d", 1).unwrap();
+        assert!(reports(&mut b).is_empty());
+        assert!(reports(&mut c).is_empty());
+        assert_eq!(waiting(&queue), ["b", "c"]);
+    }
+
+    #[test]
+    fn jobs_gone_while_waiting_do_not_take_a_slot() {
+        let mut queue = JobQueue::default();
+        let _a = join(&mut queue, "a", None, Priority::Normal, 1);
+        let b = join(&mut queue, "b", None, Priority::Normal, 1);
+        let mut c = join(&mut queue, "c", None, Priority::Normal, 1);
+        drop(b);
+
+        queue.leave("a", 1).unwrap();
+        assert_eq!(
+            reports(&mut c),
+            [JobQueueReport::InQueue(2), JobQueueReport::Ready]
+        );
+        assert_eq!(running(&queue), ["c"]);
+        assert!(queue.waiting.is_empty());
+    }
+
+    #[test]
+    fn higher_priorities_start_first() {
+        let mut queue = JobQueue::default();
+        let _a = join(&mut queue, "a", Some(1), Priority::Normal, 1);
+        let mut normal = join(&mut queue, "normal", Some(1), Priority::Normal, 1);
+        let mut high = join(&mut queue, "high", Some(1), Priority::High, 1);
+        assert_eq!(
+            reports(&mut normal),
+            [JobQueueReport::InQueue(1), JobQueueReport::InQueue(2)]
+        );
+        assert_eq!(reports(&mut high), [JobQueueReport::InQueue(1)]);
+        assert_eq!(waiting(&queue), ["high", "normal"]);
+
+        queue.leave("a", 1).unwrap();
+        assert_eq!(reports(&mut high), [JobQueueReport::Ready]);
+        assert_eq!(reports(&mut normal), [JobQueueReport::InQueue(1)]);
+    }
+
+    #[test]
+    fn users_take_turns() {
+        let mut queue = JobQueue::default();
+        let _running = join(&mut queue, "running", Some(1), Priority::Normal, 1);
+        for (uuid, user_id) in [
+            ("first1", Some(1)),
+            ("first2", Some(1)),
+            ("second1", Some(2)),
+            ("anonymous1", None),
+            ("second2", Some(2)),
+            ("anonymous2", None),
+        ] {
+            join(&mut queue, uuid, user_id, Priority::Normal, 1);
+        }
+        // User 1 already has a job running, so the others go first
+        assert_eq!(
+            waiting(&queue),
+            [
+                "second1",
+                "anonymous1",
+                "first1",
+                "second2",
+                "anonymous2",
+                "first2"
+            ]
+        );
+
+        // Priority comes before turns
+        join(&mut queue, "first3", Some(1), Priority::High, 1);
+        assert_eq!(waiting(&queue)[0], "first3");
+    }
+}
@@ -0,0 +1,200 @@
+//! Configuration file loaded from `CONFIG_PATH` (defaults to `config.toml`)
+
+use crate::{
+    auth::AuthConfig,
+    clip::{ClipConfig, DownloadConfig, OutputFormat, WorkerConfig},
+    dvr::DvrConfig,
+    segment_cache::CacheConfig,
+    storage::{StorageConfig, UploadConfig},
+};
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Profile used when a request doesn't ask for one and doesn't set `encode`
+pub const COPY_PROFILE: &str = "copy";
+/// Profile used when a request sets the legacy `encode` flag
+pub const ENCODE_PROFILE: &str = "x264";
+
+lazy_static! {
+    pub static ref CONFIG: Config = {
+        let path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".to_string());
+        Config::load(Path::new(&path)).expect("failed to load configuration")
+    };
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Named encode profiles, layered over the built-in `copy` and `x264` profiles
+    pub profiles: HashMap<String, EncodeProfile>,
+    /// Where finished clips are uploaded to
+    pub storage: StorageConfig,
+    /// How results are sent to the storage
+    pub upload: UploadConfig,
+    /// How much clip work runs at once
+    pub workers: WorkerConfig,
+    /// Limits on clip requests
+    pub clips: ClipConfig,
+    /// How callers sign in
+    pub auth: AuthConfig,
+    /// How segments are fetched from the CDNs
+    pub download: DownloadConfig,
+    /// Segments kept for overlapping clips
+    pub cache: CacheConfig,
+    /// Channels recorded continuously
+    pub dvr: DvrConfig,
+}
+impl Config {
+    /// Read and validate the configuration, falling back to defaults if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config = if path.exists() {
+            info!("loading configuration from {path}", path = path.display());
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| anyhow!("reading {path}", path = path.display()))?;
+            toml::from_str::<Self>(&contents)
+                .with_context(|| anyhow!("parsing {path}", path = path.display()))?
+        } else {
+            warn!(
+                "no configuration at {path}, using defaults",
+                path = path.display()
+            );
+            Self::default()
+        };
+
+        for (name, profile) in [
+            (COPY_PROFILE, EncodeProfile::default()),
+            (
+                ENCODE_PROFILE,
+                EncodeProfile {
+                    video_codec: "libx264".to_string(),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            config.profiles.entry(name.to_string()).or_insert(profile);
+        }
+        config
+            .workers
+            .validate()
+            .context("invalid worker configuration")?;
+        if config.dvr.depth_hours <= 0. {
+            bail!("dvr depth_hours must be positive");
+        }
+        for (name, profile) in &config.profiles {
+            profile
+                .validate()
+                .with_context(|| anyhow!("invalid encode profile {name}"))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Look up an encode profile by name
+    pub fn profile(&self, name: &str) -> Result<&EncodeProfile> {
+        self.profiles.get(name).with_context(|| {
+            let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            anyhow!(
+                "unknown encode profile {name} (available: {})",
+                names.join(", ")
+            )
+        })
+    }
+}
+
+/// How the combined segments are turned into the final clip
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodeProfile {
+    /// FFmpeg video encoder, or `copy` to keep the broadcast stream
+    pub video_codec: String,
+    pub crf: Option<u8>,
+    /// Target video bitrate (e.g. `4M`)
+    pub video_bitrate: Option<String>,
+    pub preset: Option<String>,
+    /// Multiplier for the source resolution (e.g. `0.5` for 1080p -> 540p)
+    pub scale: Option<f32>,
+    pub frame_rate: Option<f32>,
+    /// FFmpeg audio encoder, or `copy` to keep the broadcast stream
+    pub audio_codec: String,
+    /// Target audio bitrate (e.g. `128k`)
+    pub audio_bitrate: Option<String>,
+    pub container: OutputFormat,
+}
+impl Default for EncodeProfile {
+    fn default() -> Self {
+        Self {
+            video_codec: "copy".to_string(),
+            crf: None,
+            video_bitrate: None,
+            preset: None,
+            scale: None,
+            frame_rate: None,
+            audio_codec: "copy".to_string(),
+            audio_bitrate: None,
+            container: OutputFormat::Mp4,
+        }
+    }
+}
+impl EncodeProfile {
+    fn validate(&self) -> Result<()> {
+        if self.container.is_audio_only() {
+            bail!("container {} has no video", self.container.extension());
+        }
+        if self.video_codec == "copy"
+            && (self.crf.is_some()
+                || self.video_bitrate.is_some()
+                || self.preset.is_some()
+                || self.scale.is_some()
+                || self.frame_rate.is_some())
+        {
+            bail!("video options require a video codec other than copy");
+        }
+        if self.audio_codec == "copy" && self.audio_bitrate.is_some() {
+            bail!("audio bitrate requires an audio codec other than copy");
+        }
+        if self.scale.is_some_and(|scale| scale <= 0.) {
+            bail!("scale must be positive");
+        }
+        if self.frame_rate.is_some_and(|frame_rate| frame_rate <= 0.) {
+            bail!("frame rate must be positive");
+        }
+        Ok(())
+    }
+
+    /// Whether the video is re-encoded
+    pub fn encodes_video(&self) -> bool {
+        self.video_codec != "copy"
+    }
+
+    /// FFmpeg output options for this profile
+    pub fn output_options(&self) -> Vec<(&'static str, String)> {
+        let mut options = vec![("c:v", self.video_codec.clone())];
+        if let Some(crf) = self.crf {
+            options.push(("crf", crf.to_string()));
+        }
+        if let Some(video_bitrate) = &self.video_bitrate {
+            options.push(("b:v", video_bitrate.clone()));
+        }
+        if let Some(preset) = &self.preset {
+            options.push(("preset", preset.clone()));
+        }
+        if let Some(scale) = self.scale {
+            // Keep the height even for chroma subsampling
+            options.push(("vf", format!("scale=trunc(iw*{scale}/2)*2:-2")));
+        }
+        if let Some(frame_rate) = self.frame_rate {
+            options.push(("r", frame_rate.to_string()));
+        }
+        options.push(("c:a", self.audio_codec.clone()));
+        if let Some(audio_bitrate) = &self.audio_bitrate {
+            options.push(("b:a", audio_bitrate.clone()));
+        }
+        options
+    }
+}
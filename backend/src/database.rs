@@ -1,13 +1,15 @@
-use crate::filters::ServerError;
+use crate::{clip::Stage, error::ApiError};
 
 use crate::consts::DATABASE_URL;
-use anyhow::{anyhow, Context as _, Result};
-use chrono::NaiveDateTime;
+use anyhow::{anyhow, bail, Context as _, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::{
+    pg::Pg,
     prelude::*,
     r2d2::{ConnectionManager, Pool, PooledConnection},
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use warp::{reject, Filter};
 
 pub type PoolPg = Pool<ConnectionManager<PgConnection>>;
@@ -30,6 +32,15 @@ pub struct Database {
     pub connection: PooledPg,
 }
 impl Database {
+    /// Take a connection from the pool
+    pub fn connect(pool: &PoolPg) -> Result<Self, ApiError> {
+        match pool.get() {
+            Ok(connection) => Ok(Self { connection }),
+            Err(e) => Err(ApiError::internal(anyhow!(
+                "failed to access database: {e}"
+            ))),
+        }
+    }
     pub fn create_recording(&mut self, recording: &RecordingUpdate) -> Result<Recording> {
         let recording = diesel::insert_into(crate::schema::recordings::table)
             .values(recording)
@@ -42,15 +53,141 @@ impl Database {
         diesel::delete(recordings.filter(id.eq(target_id))).execute(&mut self.connection)?;
         Ok(())
     }
-    pub fn get_recordings(&mut self, start: i64, count: i64) -> Result<Vec<Recording>> {
+    pub fn get_recording(&mut self, target_uuid: &str) -> Result<Option<Recording>> {
+        use crate::schema::recordings::dsl::*;
+        let recording = recordings
+            .filter(uuid.eq(target_uuid))
+            .first(&mut self.connection)
+            .optional()?;
+        Ok(recording)
+    }
+    /// A page of the recordings matching a query, with the total number matching
+    pub fn get_recordings(
+        &mut self,
+        query: &RecordingsQuery,
+        cursor: Option<&Cursor>,
+    ) -> Result<RecordingsPage> {
+        use crate::schema::recordings::dsl::*;
+        let total = query.filtered().count().get_result(&mut self.connection)?;
+
+        let count = query.page_size();
+        let ascending = query.order == SortOrder::Asc;
+        let mut page = query.filtered();
+        page = match (query.sort, ascending) {
+            (RecordingSort::Id, true) => page.order_by(id.asc()),
+            (RecordingSort::Id, false) => page.order_by(id.desc()),
+            (RecordingSort::RecStart, true) => page.order_by((rec_start.asc(), id.asc())),
+            (RecordingSort::RecStart, false) => page.order_by((rec_start.desc(), id.desc())),
+        };
+        page = match (cursor, query.sort, ascending) {
+            (Some(cursor), RecordingSort::Id, true) => page.filter(id.gt(cursor.id)),
+            (Some(cursor), RecordingSort::Id, false) => page.filter(id.lt(cursor.id)),
+            (Some(cursor), RecordingSort::RecStart, true) => page.filter(
+                rec_start
+                    .gt(cursor.rec_start)
+                    .or(rec_start.eq(cursor.rec_start).and(id.gt(cursor.id))),
+            ),
+            (Some(cursor), RecordingSort::RecStart, false) => page.filter(
+                rec_start
+                    .lt(cursor.rec_start)
+                    .or(rec_start.eq(cursor.rec_start).and(id.lt(cursor.id))),
+            ),
+            (None, ..) => page.offset(query.start.unwrap_or(0).max(0)),
+        };
+        // One more than the page tells whether there's a next one
+        let mut recordings_list: Vec<Recording> =
+            page.limit(count + 1).load(&mut self.connection)?;
+        let next_cursor = if recordings_list.len() as i64 > count {
+            recordings_list.truncate(count as usize);
+            recordings_list.last().map(|last| {
+                Cursor {
+                    rec_start: last.rec_start,
+                    id: last.id,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        Ok(RecordingsPage {
+            recordings: recordings_list,
+            total,
+            next_cursor,
+        })
+    }
+    /// Recordings that haven't completed or failed, oldest first
+    pub fn get_unfinished_recordings(&mut self) -> Result<Vec<Recording>> {
         use crate::schema::recordings::dsl::*;
         let recordings_list = recordings
-            .offset(start)
-            .limit(count)
-            .order_by(id.desc())
+            .filter(stage.lt(Stage::Complete as i32))
+            .order_by(id.asc())
             .load(&mut self.connection)?;
         Ok(recordings_list)
     }
+    pub fn create_user(&mut self, user: &NewUser) -> Result<User> {
+        let user = diesel::insert_into(crate::schema::users::table)
+            .values(user)
+            .get_result(&mut self.connection)
+            .context("failed to insert user")?;
+        Ok(user)
+    }
+    pub fn get_user(&mut self, target_id: UserId) -> Result<Option<User>> {
+        use crate::schema::users::dsl::*;
+        let user = users
+            .filter(id.eq(target_id))
+            .first(&mut self.connection)
+            .optional()?;
+        Ok(user)
+    }
+    pub fn get_user_by_name(&mut self, name: &str) -> Result<Option<User>> {
+        use crate::schema::users::dsl::*;
+        let user = users
+            .filter(username.eq(name))
+            .first(&mut self.connection)
+            .optional()?;
+        Ok(user)
+    }
+    pub fn create_api_token(&mut self, token: &NewApiToken) -> Result<ApiToken> {
+        let token = diesel::insert_into(crate::schema::api_tokens::table)
+            .values(token)
+            .get_result(&mut self.connection)
+            .context("failed to insert API token")?;
+        Ok(token)
+    }
+    /// A user's API tokens, newest first
+    pub fn get_api_tokens(&mut self, owner: UserId) -> Result<Vec<ApiToken>> {
+        use crate::schema::api_tokens::dsl::*;
+        let tokens = api_tokens
+            .filter(user_id.eq(owner))
+            .order_by(id.desc())
+            .load(&mut self.connection)?;
+        Ok(tokens)
+    }
+    /// The token with this hash and its user
+    pub fn get_api_token_by_hash(&mut self, hash: &str) -> Result<Option<(ApiToken, User)>> {
+        use crate::schema::{api_tokens, users};
+        let token = api_tokens::table
+            .inner_join(users::table)
+            .filter(api_tokens::token_hash.eq(hash))
+            .select((ApiToken::as_select(), User::as_select()))
+            .first(&mut self.connection)
+            .optional()?;
+        Ok(token)
+    }
+    pub fn touch_api_token(&mut self, target_id: i32) -> Result<()> {
+        use crate::schema::api_tokens::dsl::*;
+        diesel::update(api_tokens.filter(id.eq(target_id)))
+            .set(last_used.eq(diesel::dsl::now))
+            .execute(&mut self.connection)?;
+        Ok(())
+    }
+    /// Delete one of a user's tokens, returning whether it existed
+    pub fn delete_api_token(&mut self, owner: UserId, target_id: i32) -> Result<bool> {
+        use crate::schema::api_tokens::dsl::*;
+        let deleted = diesel::delete(api_tokens.filter(id.eq(target_id).and(user_id.eq(owner))))
+            .execute(&mut self.connection)?;
+        Ok(deleted > 0)
+    }
     pub fn update_recording(&mut self, recording: &RecordingUpdate) -> Result<Recording> {
         use crate::schema::recordings::dsl::*;
         let recording = diesel::update(recordings.filter(uuid.eq(&recording.uuid)))
@@ -59,6 +196,25 @@ impl Database {
             .context("failed to update recording row")?;
         Ok(recording)
     }
+    /// Move a recording to `new_stage` if it's still at `expected`, returning whether it was.
+    /// Of concurrent callers expecting the same stage, only one succeeds.
+    pub fn set_stage_if(
+        &mut self,
+        target_uuid: &str,
+        expected: Stage,
+        new_stage: Stage,
+    ) -> Result<bool> {
+        use crate::schema::recordings::dsl::*;
+        let updated = diesel::update(
+            recordings
+                .filter(uuid.eq(target_uuid))
+                .filter(stage.eq(expected as i32)),
+        )
+        .set(stage.eq(new_stage as i32))
+        .execute(&mut self.connection)
+        .context("failed to update recording stage")?;
+        Ok(updated > 0)
+    }
 }
 
 #[derive(Queryable, Selectable, Serialize, Clone)]
@@ -74,6 +230,17 @@ pub struct Recording {
     pub short_status: String,
     pub stage: i32,
     pub channel: String,
+    /// Extension of the result (`mp4`, or `m4a`/`mp3`/`opus` for audio-only clips)
+    pub output_format: String,
+    /// Name of the encode profile the clip was produced with
+    pub encode_profile: String,
+    /// FFmpeg output options the clip was produced with
+    pub encode_options: String,
+    /// Requested quality (e.g. `best`, `720p`, `audio-only`)
+    pub quality: String,
+    /// Whether the result goes to storage rather than staying in the temp directory, for
+    /// users without `can_upload`
+    pub uploaded: bool,
 }
 #[derive(Insertable, AsChangeset)]
 #[diesel(table_name = crate::schema::recordings)]
@@ -87,9 +254,222 @@ pub struct RecordingUpdate {
     pub short_status: String,
     pub stage: i32,
     pub channel: String,
+    /// Extension of the result (`mp4`, or `m4a`/`mp3`/`opus` for audio-only clips)
+    pub output_format: String,
+    /// Name of the encode profile the clip was produced with
+    pub encode_profile: String,
+    /// FFmpeg output options the clip was produced with
+    pub encode_options: String,
+    /// Requested quality (e.g. `best`, `720p`, `audio-only`)
+    pub quality: String,
+    /// Whether the result goes to storage rather than staying in the temp directory, for
+    /// users without `can_upload`
+    pub uploaded: bool,
+}
+
+impl From<Recording> for RecordingUpdate {
+    fn from(recording: Recording) -> Self {
+        Self {
+            user_id: recording.user_id,
+            uuid: recording.uuid,
+            rec_start: recording.rec_start,
+            rec_end: recording.rec_end,
+            status: recording.status,
+            short_status: recording.short_status,
+            stage: recording.stage,
+            channel: recording.channel,
+            output_format: recording.output_format,
+            encode_profile: recording.encode_profile,
+            encode_options: recording.encode_options,
+            quality: recording.quality,
+            uploaded: recording.uploaded,
+        }
+    }
+}
+
+/// Group of stages to filter recordings by
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingState {
+    /// Queued or being clipped
+    InProgress,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingSort {
+    /// Order of creation
+    #[default]
+    Id,
+    RecStart,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query of `GET /list-recordings`
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingsQuery {
+    pub channel: Option<String>,
+    pub state: Option<RecordingState>,
+    pub user_id: Option<UserId>,
+    /// Recordings starting at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Recordings ending at or before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Text the status contains, ignoring case
+    pub search: Option<String>,
+    pub sort: RecordingSort,
+    pub order: SortOrder,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Offset of the page when there's no cursor
+    pub start: Option<i64>,
+    /// Recordings per page
+    pub count: Option<i64>,
 }
+impl RecordingsQuery {
+    const DEFAULT_COUNT: i64 = 15;
+    const MAX_COUNT: i64 = 100;
 
-// todo: users
+    fn page_size(&self) -> i64 {
+        self.count
+            .unwrap_or(Self::DEFAULT_COUNT)
+            .clamp(1, Self::MAX_COUNT)
+    }
+
+    /// Recordings matching the filters, before ordering and paging
+    fn filtered(&self) -> crate::schema::recordings::BoxedQuery<'static, Pg> {
+        use crate::schema::recordings::dsl::*;
+        let mut query = recordings.into_boxed();
+        if let Some(target) = &self.channel {
+            query = query.filter(channel.eq(target.clone()));
+        }
+        if let Some(state) = self.state {
+            query = match state {
+                RecordingState::InProgress => query.filter(stage.lt(Stage::Complete as i32)),
+                RecordingState::Completed => query.filter(stage.eq(Stage::Complete as i32)),
+                RecordingState::Failed => query.filter(stage.between(
+                    Stage::FailedNondescript as i32,
+                    Stage::UploadingFailed as i32,
+                )),
+                RecordingState::Cancelled => query.filter(stage.eq(Stage::Cancelled as i32)),
+            };
+        }
+        if let Some(target) = self.user_id {
+            query = query.filter(user_id.eq(target));
+        }
+        if let Some(from) = self.from {
+            query = query.filter(rec_start.ge(from.naive_utc()));
+        }
+        if let Some(to) = self.to {
+            query = query.filter(rec_end.le(to.naive_utc()));
+        }
+        if let Some(search) = self.search.as_deref().filter(|search| !search.is_empty()) {
+            let escaped = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            query = query.filter(status.ilike(format!("%{escaped}%")));
+        }
+        query
+    }
+}
+
+/// Position after the last recording of a page
+pub struct Cursor {
+    rec_start: NaiveDateTime,
+    id: i32,
+}
+impl Cursor {
+    fn encode(&self) -> String {
+        BASE64_URL.encode(format!(
+            "{}:{}",
+            self.rec_start.and_utc().timestamp_micros(),
+            self.id
+        ))
+    }
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let decoded = String::from_utf8(BASE64_URL.decode(cursor)?)?;
+        let Some((micros, id)) = decoded.split_once(':') else {
+            bail!("malformed cursor");
+        };
+        Ok(Self {
+            rec_start: DateTime::from_timestamp_micros(micros.parse()?)
+                .context("cursor time out of range")?
+                .naive_utc(),
+            id: id.parse()?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct RecordingsPage {
+    pub recordings: Vec<Recording>,
+    /// Recordings matching the filters across all pages
+    pub total: i64,
+    /// Cursor of the next page, `None` on the last one
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Serialize, Clone, Debug)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct User {
+    pub id: UserId,
+    pub username: String,
+    /// Longest clip the user can request
+    pub max_length_seconds: i32,
+    pub can_upload: bool,
+    pub can_delete: bool,
+    pub superuser: bool,
+    /// Argon2 hash of the password, `None` when the user can't sign in with one
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+}
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewUser {
+    pub username: String,
+    pub password_hash: Option<String>,
+    pub superuser: bool,
+}
+
+#[derive(Queryable, Selectable, Serialize, Clone, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: UserId,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    /// What the token can do (`full` or `clip`)
+    pub scope: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used: Option<NaiveDateTime>,
+}
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewApiToken {
+    pub user_id: UserId,
+    pub name: String,
+    pub token_hash: String,
+    pub scope: String,
+    pub expires_at: Option<NaiveDateTime>,
+}
 
 /// Filter for accessing the database
 pub fn with_database(
@@ -97,12 +477,71 @@ pub fn with_database(
 ) -> impl Filter<Extract = (Database,), Error = warp::Rejection> + Clone {
     warp::any()
         .map(move || pool.clone())
-        .and_then(|pool: PoolPg| async move {
-            match pool.get() {
-                Ok(pool) => Ok(Database { connection: pool }),
-                Err(e) => Err(reject::custom(ServerError::new(anyhow!(
-                    "failed to access database: {e}"
-                )))),
+        .and_then(|pool: PoolPg| async move { Database::connect(&pool).map_err(reject::custom) })
+}
+
+/// Databases for tests
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::{sync::OnceLock, time::Duration};
+
+    use diesel::{connection::SimpleConnection as _, r2d2::CustomizeConnection};
+
+    /// Schema recreated with the migrations on every test run
+    const SCHEMA: &str = "bbcd_test";
+
+    #[derive(Debug)]
+    struct SearchPath;
+    impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for SearchPath {
+        fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+            connection
+                .batch_execute(&format!("set search_path to {SCHEMA}"))
+                .map_err(diesel::r2d2::Error::QueryError)
+        }
+    }
+
+    /// Pool on the database in `TEST_DATABASE_URL`, with the migrations applied to a scratch schema
+    pub fn pool() -> PoolPg {
+        static POOL: OnceLock<PoolPg> = OnceLock::new();
+        POOL.get_or_init(|| {
+            let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL isn't set");
+            let mut connection = PgConnection::establish(&url).expect("failed to connect");
+            connection
+                .batch_execute(&format!(
+                    "drop schema if exists {SCHEMA} cascade; create schema {SCHEMA}; \
+                     set search_path to {SCHEMA}"
+                ))
+                .expect("failed to create the test schema");
+            let mut migrations =
+                std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .filter(|path| path.is_dir())
+                    .collect::<Vec<_>>();
+            migrations.sort();
+            for migration in migrations {
+                let up = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+                connection
+                    .batch_execute(&up)
+                    .unwrap_or_else(|e| panic!("failed to run {}: {e}", migration.display()));
             }
+
+            PoolPg::builder()
+                .max_size(8)
+                .connection_customizer(Box::new(SearchPath))
+                .build(ConnectionManager::new(url))
+                .expect("failed to create pool")
         })
+        .clone()
+    }
+
+    /// Pool that never connects, for requests rejected before reaching the database
+    pub fn unreachable_pool() -> PoolPg {
+        PoolPg::builder()
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://127.0.0.1:1/unreachable"))
+    }
 }
@@ -0,0 +1,268 @@
+//! Rolling buffer of the latest segments of configured channels, so clips can reach further
+//! back than the CDNs' timeshift window
+
+use crate::{
+    clip::{download, download_init_segment, representation_file_name, TEMP_DIRECTORY},
+    config::CONFIG,
+    manifest::{Quality, Representation},
+    sources,
+};
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use chrono::Utc;
+use futures_util::{stream, StreamExt as _};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{
+    fs::{create_dir_all, read_dir, remove_file},
+    time::sleep,
+};
+
+/// Directory of the buffer under the temp directory
+pub const DIRECTORY_NAME: &str = "dvr";
+/// Wait between passes when the manifest can't be fetched
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `[dvr]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DvrConfig {
+    /// Channels recorded continuously
+    pub channels: Vec<String>,
+    /// How far back the buffer reaches
+    pub depth_hours: f64,
+    /// Representations recorded for each channel
+    pub quality: Quality,
+}
+impl Default for DvrConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            depth_hours: 6.,
+            quality: Quality::Best,
+        }
+    }
+}
+
+/// Path of a buffered segment
+pub fn segment_path(channel: &str, representation: &Representation, segment_idx: usize) -> PathBuf {
+    representation_directory(channel, representation).join(format!("{segment_idx}.m4s"))
+}
+fn representation_directory(channel: &str, representation: &Representation) -> PathBuf {
+    PathBuf::from(TEMP_DIRECTORY)
+        .join(DIRECTORY_NAME)
+        .join(channel)
+        .join(representation_file_name(representation))
+}
+
+/// What has been buffered of a representation
+struct Buffered {
+    /// Next segment index to fetch
+    next: usize,
+    /// Earlier segments that failed to download, retried on every pass
+    missing: BTreeSet<usize>,
+}
+impl Buffered {
+    /// Segment indices to fetch up to `latest`, skipping those before `first`
+    fn pending(&self, first: usize, latest: usize) -> Vec<usize> {
+        self.missing
+            .range(first..)
+            .copied()
+            .chain(self.next.max(first)..=latest)
+            .collect()
+    }
+}
+
+/// Record every configured channel until the process exits
+pub async fn record_all() {
+    let config = &CONFIG.dvr;
+    for channel in &config.channels {
+        if sources::get(channel).is_none() {
+            warn!("dvr: {channel} isn't in the sources, it will be recorded once it is");
+        }
+    }
+    if !config.channels.is_empty() {
+        info!(
+            "dvr: recording {channels} with {depth_hours}h of history",
+            channels = config.channels.join(", "),
+            depth_hours = config.depth_hours
+        );
+    }
+    futures_util::future::join_all(config.channels.iter().map(|channel| record(channel))).await;
+}
+
+/// Keep a channel's buffer up to date
+async fn record(channel: &str) {
+    // Progress by representation ID
+    let mut buffered = HashMap::new();
+    loop {
+        let interval = match record_once(channel, &mut buffered).await {
+            Ok(interval) => interval,
+            Err(e) => {
+                warn!("dvr: recording {channel} failed: {e:?}");
+                RETRY_INTERVAL
+            }
+        };
+        sleep(interval).await;
+    }
+}
+
+/// Fetch the segments published since the last pass and drop those older than the depth.
+/// Returns how long to wait before the next pass.
+async fn record_once(channel: &str, buffered: &mut HashMap<String, Buffered>) -> Result<Duration> {
+    let config = &CONFIG.dvr;
+    let source = sources::get(channel).with_context(|| anyhow!("unknown channel {channel}"))?;
+    let manifest = source.manifest().await?;
+    let representations = manifest.select(&config.quality)?;
+
+    let now = Utc::now().timestamp() as usize;
+    let depth = (config.depth_hours * 60. * 60.) as usize;
+    // Nothing from before the CDN's timeshift window can be fetched
+    let reachable = manifest.time_shift_buffer_depth.map_or(depth, |buffer| {
+        (buffer.num_seconds().max(0) as usize).min(depth)
+    });
+
+    let mut interval = RETRY_INTERVAL;
+    for representation in representations {
+        interval = interval.min(Duration::from_secs_f64(representation.segment_duration()));
+
+        download_init_segment(channel, &source.url_prefixes, representation).await?;
+
+        // The segment being published now isn't complete yet
+        let latest = representation.segment_idx(now).saturating_sub(1);
+        let oldest = representation.segment_idx(now.saturating_sub(depth));
+        let first = representation.segment_idx(now.saturating_sub(reachable));
+        let progress = buffered
+            .entry(representation.id.clone())
+            .or_insert_with(|| Buffered {
+                next: first,
+                missing: BTreeSet::new(),
+            });
+        let pending = progress.pending(first, latest);
+
+        let directory = representation_directory(channel, representation);
+        create_dir_all(&directory).await?;
+        let failures = stream::iter(pending.iter().copied())
+            .map(|segment_idx| {
+                let path = segment_path(channel, representation, segment_idx);
+                let urls = source
+                    .url_prefixes
+                    .iter()
+                    .map(|url_prefix| representation.segment_url(url_prefix, segment_idx))
+                    .collect::<Result<Vec<_>>>();
+                async move {
+                    let result = match urls {
+                        Ok(urls) => download(&urls, &path).await,
+                        Err(e) => Err(e),
+                    };
+                    (segment_idx, result)
+                }
+            })
+            .buffer_unordered(CONFIG.workers.downloads_per_job)
+            .filter_map(|(segment_idx, result)| async move { Some((segment_idx, result.err()?)) })
+            .collect::<Vec<_>>()
+            .await;
+        if let Some((_, e)) = failures.first() {
+            warn!(
+                "dvr: {channel}: {count} segments of {id} are missing, retrying next pass: {e:?}",
+                count = failures.len(),
+                id = representation.id
+            );
+        }
+        if !pending.is_empty() {
+            debug!(
+                "dvr: {channel}: buffered {count} {id} segments up to {latest}",
+                count = pending.len() - failures.len(),
+                id = representation.id
+            );
+        }
+        progress.next = progress.next.max(latest + 1);
+        progress.missing = failures
+            .into_iter()
+            .map(|(segment_idx, _)| segment_idx)
+            .collect();
+
+        prune(&directory, oldest).await?;
+    }
+
+    Ok(interval)
+}
+
+/// Remove the segments before `oldest` from a representation's buffer, along with the
+/// partial downloads left by failed passes
+async fn prune(directory: &Path, oldest: usize) -> Result<()> {
+    let mut entries = read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let segment_idx = name
+            .split('.')
+            .next()
+            .and_then(|stem| stem.parse::<usize>().ok());
+        let partial = path
+            .extension()
+            .is_some_and(|extension| extension == "part");
+        if partial || segment_idx.is_some_and(|segment_idx| segment_idx < oldest) {
+            remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    #[test]
+    fn failed_segments_are_retried() {
+        let mut buffered = Buffered {
+            next: 10,
+            missing: BTreeSet::new(),
+        };
+        assert_eq!(buffered.pending(5, 12), [10, 11, 12]);
+        // Nothing new yet
+        assert!(buffered.pending(5, 9).is_empty());
+
+        buffered.missing = BTreeSet::from([4, 7, 8]);
+        assert_eq!(buffered.pending(5, 12), [7, 8, 10, 11, 12]);
+        assert_eq!(buffered.pending(5, 9), [7, 8]);
+        // Segments that fell out of reach are given up on, as are the ones skipped
+        assert_eq!(buffered.pending(11, 12), [11, 12]);
+    }
+
+    #[tokio::test]
+    async fn prunes_old_segments_and_partial_downloads() {
+        let directory = std::env::temp_dir().join(format!("bbcd-test-dvr-{}", Uuid::new_v4()));
+        create_dir_all(&directory).await.unwrap();
+        for name in [
+            "4.m4s",
+            "5.m4s",
+            "6.m4s",
+            "3.m4s.part",
+            "7.m4s.part",
+            "notes",
+        ] {
+            tokio::fs::write(directory.join(name), b"").await.unwrap();
+        }
+
+        prune(&directory, 5).await.unwrap();
+        let mut entries = read_dir(&directory).await.unwrap();
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        names.sort();
+        assert_eq!(names, ["5.m4s", "6.m4s", "notes"]);
+        tokio::fs::remove_dir_all(&directory).await.unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! Errors returned by the HTTP API and the recovery filter turning rejections into JSON
+
+use crate::clip::FieldError;
+
+use std::{convert::Infallible, error::Error as _, fmt::Debug};
+
+use log::{error, warn};
+use serde::Serialize;
+use uuid::Uuid;
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, Reject, UnsupportedMediaType,
+    },
+    Rejection, Reply,
+};
+
+/// Why a request failed
+pub enum ApiError {
+    /// The request is malformed or asks for something impossible
+    Validation {
+        message: String,
+        fields: Vec<FieldError>,
+    },
+    NotFound(String),
+    /// Missing or wrong credentials
+    Unauthorized(String),
+    /// Valid credentials without the permission needed
+    Forbidden(String),
+    /// The resource isn't in a state allowing the request
+    Conflict(String),
+    /// A CDN or other upstream service failed
+    Upstream(anyhow::Error),
+    Internal(anyhow::Error),
+}
+impl ApiError {
+    pub fn validation<M: ToString>(message: M) -> Self {
+        Self::Validation {
+            message: message.to_string(),
+            fields: vec![],
+        }
+    }
+    /// Rejection of a request with invalid fields
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let message = fields
+            .iter()
+            .map(|error| format!("{} {}", error.field, error.message))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self::Validation { message, fields }
+    }
+    pub fn not_found<M: ToString>(message: M) -> Self {
+        Self::NotFound(message.to_string())
+    }
+    pub fn unauthorized<M: ToString>(message: M) -> Self {
+        Self::Unauthorized(message.to_string())
+    }
+    pub fn forbidden<M: ToString>(message: M) -> Self {
+        Self::Forbidden(message.to_string())
+    }
+    pub fn conflict<M: ToString>(message: M) -> Self {
+        Self::Conflict(message.to_string())
+    }
+    pub fn upstream<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Upstream(error.into())
+    }
+    pub fn internal<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Internal(error.into())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation { .. } => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable name of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation { .. } => "validation_failed",
+            Self::NotFound(_) => "not_found",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::Conflict(_) => "conflict",
+            Self::Upstream(_) => "upstream_failure",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Message shown to clients; server-side details stay in the logs
+    pub fn message(&self) -> String {
+        match self {
+            Self::Validation { message, .. }
+            | Self::NotFound(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::Conflict(message) => message.clone(),
+            Self::Upstream(e) => format!("upstream request failed: {e}"),
+            Self::Internal(_) => "internal server error".to_string(),
+        }
+    }
+}
+impl Debug for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upstream(e) | Self::Internal(e) => write!(f, "{code}: {e:?}", code = self.code()),
+            _ => write!(
+                f,
+                "{code}: {message}",
+                code = self.code(),
+                message = self.message()
+            ),
+        }
+    }
+}
+impl Reject for ApiError {}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    request_id: String,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    fields: &'a [FieldError],
+}
+
+/// Turn the rejection of a request into a JSON error
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let converted;
+    let error = match rejection.find::<ApiError>() {
+        Some(error) => error,
+        None => {
+            converted = from_warp_rejection(&rejection);
+            &converted
+        }
+    };
+
+    // Lets an error reported by a user be found in the logs
+    let request_id = Uuid::new_v4().to_string();
+    match error {
+        ApiError::Upstream(_) => warn!("request {request_id} failed: {error:?}"),
+        ApiError::Internal(_) => error!("request {request_id} failed: {error:?}"),
+        _ => {}
+    }
+
+    let fields = match error {
+        ApiError::Validation { fields, .. } => fields.as_slice(),
+        _ => &[],
+    };
+    let body = ErrorBody {
+        code: error.code(),
+        message: error.message(),
+        request_id: request_id.clone(),
+        fields,
+    };
+    let mut response = warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&body), error.status()),
+        "x-request-id",
+        request_id,
+    )
+    .into_response();
+    if matches!(error, ApiError::Unauthorized(_)) {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"bbcd\""),
+        );
+    }
+    Ok(response)
+}
+
+/// Equivalent of warp's own rejections
+fn from_warp_rejection(rejection: &Rejection) -> ApiError {
+    // Every route rejects requests for other paths, so a wrong method usually means a wrong path
+    if rejection.is_not_found() || rejection.find::<MethodNotAllowed>().is_some() {
+        return ApiError::not_found("no such endpoint");
+    }
+    if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        let message = e
+            .source()
+            .map_or_else(|| e.to_string(), ToString::to_string);
+        return ApiError::validation(format!("invalid body: {message}"));
+    }
+    if let Some(e) = rejection.find::<InvalidQuery>() {
+        return ApiError::validation(e);
+    }
+    if let Some(e) = rejection.find::<MissingHeader>() {
+        return ApiError::validation(e);
+    }
+    if let Some(e) = rejection.find::<InvalidHeader>() {
+        return ApiError::validation(e);
+    }
+    if let Some(e) = rejection.find::<PayloadTooLarge>() {
+        return ApiError::validation(e);
+    }
+    if let Some(e) = rejection.find::<LengthRequired>() {
+        return ApiError::validation(e);
+    }
+    if let Some(e) = rejection.find::<UnsupportedMediaType>() {
+        return ApiError::validation(e);
+    }
+    ApiError::internal(anyhow::anyhow!("unhandled rejection: {rejection:?}"))
+}
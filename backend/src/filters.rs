@@ -1,40 +1,39 @@
 use crate::{
-    clip::{clip, ffmpeg_progress_update_handler, ClipParameters, FfmpegProgressChannels},
-    database::{with_database, Database, PoolPg},
+    auth::{
+        authorize_owner, generate_token, hash_token, with_password_user, with_permission,
+        with_signed_in_user, with_user, Permission, TokenParameters,
+    },
+    clip::{
+        cancel_clip, clip, ffmpeg_progress_update_handler, output_path, remove_clip_files,
+        retry_clip, ClipParameters, FfmpegProgressChannels, FieldError, OutputFormat, Stage,
+        TEMP_DIRECTORY,
+    },
+    config::CONFIG,
+    database::{
+        with_database, Cursor, Database, NewApiToken, PoolPg, Recording, RecordingsQuery, User,
+        UserId,
+    },
+    error::ApiError,
+    health, sources,
+    storage::{clip_key, LocalDirectory, ObjectNotFound, Storage, StorageBackend},
     tree::get_warp_logger,
-    websocket_callbacks::{on_connect, on_disconnect, on_message},
+    websocket_callbacks::{alert_clients_of_deletion, on_connect, on_disconnect, on_message},
     websocket_connection::handle_connection,
     ClientConnections,
 };
 
-use std::{collections::HashMap, fmt::Debug, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 use anyhow::anyhow;
+use log::error;
 use serde::de::DeserializeOwned;
 use tokio::runtime::Handle;
 use uuid::Uuid;
-use warp::{reject::Reject, Filter};
-
-/// Wrapper for a Warp rejection message
-pub struct ServerError {
-    message: String,
-}
-impl ServerError {
-    pub fn new<M>(message: M) -> Self
-    where
-        M: ToString,
-    {
-        Self {
-            message: message.to_string(),
-        }
-    }
-}
-impl Debug for ServerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{message}", message = self.message)
-    }
-}
-impl Reject for ServerError {}
+use warp::{
+    http::{header, Response, StatusCode},
+    hyper::Body,
+    Filter,
+};
 
 /// Filter for accepting a thread-safe value in a handler
 pub fn with<T>(something: T) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
@@ -70,19 +69,26 @@ pub fn list_recordings(
     warp::get()
         .and(warp::path!("list-recordings"))
         .and(warp::path::end())
-        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::query::<RecordingsQuery>())
+        // Turns anonymous callers away unless `[auth] allow_anonymous` is set
+        .and(with_user(pool.clone()))
         .and(with_database(pool))
         .and_then(
-            |query: HashMap<String, String>, mut database: Database| async move {
-                let (start, count) = (query.get("start"), query.get("count"));
-                let start = start.map(|start| start.parse().ok()).flatten().unwrap_or(0);
-                let count = count
-                    .map(|count| count.parse().ok())
-                    .flatten()
-                    .unwrap_or(15);
-                match database.get_recordings(start, count) {
-                    Ok(videos) => Ok(warp::reply::json(&videos)),
-                    Err(e) => Err(warp::reject::custom(ServerError::new(anyhow!(
+            |query: RecordingsQuery, _: Option<User>, mut database: Database| async move {
+                let cursor = query
+                    .cursor
+                    .as_deref()
+                    .map(Cursor::decode)
+                    .transpose()
+                    .map_err(|e| {
+                        ApiError::invalid_fields(vec![FieldError {
+                            field: "cursor",
+                            message: e.to_string(),
+                        }])
+                    })?;
+                match database.get_recordings(&query, cursor.as_ref()) {
+                    Ok(page) => Ok(warp::reply::json(&page)),
+                    Err(e) => Err(warp::reject::custom(ApiError::internal(anyhow!(
                         "failed to fetch videos: {e}"
                     )))),
                 }
@@ -92,10 +98,144 @@ pub fn list_recordings(
         .with(warp::log::custom(get_warp_logger))
 }
 
+/// GET /sources
+pub fn list_sources() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("sources"))
+        .and(warp::path::end())
+        .map(|| {
+            let catalogue = sources::all();
+            let mut sources = catalogue.values().collect::<Vec<_>>();
+            sources.sort_by_key(|source| source.id);
+            warp::reply::json(
+                &sources
+                    .into_iter()
+                    .map(|source| {
+                        serde_json::json!({
+                            "name": source.name,
+                            "id": source.id,
+                            "group": source.group,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// GET /sources/health (superusers only)
+pub fn sources_health(
+    pool: PoolPg,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("sources" / "health"))
+        .and(warp::path::end())
+        .and(with_permission(pool, Permission::Admin))
+        .map(|_: User| {
+            // Origins of each source in the order new segments try them
+            let catalogue = sources::all();
+            let sources = catalogue
+                .iter()
+                .map(|(name, source)| (name.clone(), source.ranked_url_prefixes()))
+                .collect::<HashMap<_, _>>();
+            warp::reply::json(&serde_json::json!({
+                "origins": health::snapshot(),
+                "sources": sources,
+            }))
+        })
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// POST /tokens, returning the new API token, which can't be retrieved again
+pub fn create_token(
+    pool: PoolPg,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("tokens"))
+        .and(warp::path::end())
+        .and(with_json_body::<TokenParameters>())
+        .and(with_password_user(pool.clone()))
+        .and(with_database(pool))
+        .and_then(
+            |parameters: TokenParameters, user_id: UserId, mut database: Database| async move {
+                let name = parameters.name.trim();
+                if name.is_empty() || name.len() > 64 {
+                    Err(ApiError::validation("names are 1 to 64 characters"))?;
+                }
+                let expires_at = parameters.expires_at()?;
+                let token = generate_token();
+                let row = database
+                    .create_api_token(&NewApiToken {
+                        user_id,
+                        name: name.to_string(),
+                        token_hash: hash_token(&token),
+                        scope: parameters.scope.as_str().to_string(),
+                        expires_at,
+                    })
+                    .map_err(ApiError::internal)?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&serde_json::json!({
+                    "token": token,
+                    "details": row,
+                })))
+            },
+        )
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// GET /tokens
+pub fn list_tokens(
+    pool: PoolPg,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("tokens"))
+        .and(warp::path::end())
+        .and(with_password_user(pool.clone()))
+        .and(with_database(pool))
+        .and_then(|user_id: UserId, mut database: Database| async move {
+            let tokens = database
+                .get_api_tokens(user_id)
+                .map_err(ApiError::internal)?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&tokens))
+        })
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// DELETE /tokens/{id}
+pub fn revoke_token(
+    pool: PoolPg,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path!("tokens" / i32))
+        .and(warp::path::end())
+        .and(with_password_user(pool.clone()))
+        .and(with_database(pool))
+        .and_then(
+            |id: i32, user_id: UserId, mut database: Database| async move {
+                if !database
+                    .delete_api_token(user_id, id)
+                    .map_err(ApiError::internal)?
+                {
+                    Err(ApiError::not_found(format!("no API token {id}")))?;
+                }
+                Ok::<_, warp::Rejection>(warp::reply::with_status(
+                    "revoked".to_string(),
+                    StatusCode::OK,
+                ))
+            },
+        )
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
 /// POST /clip
 pub fn clip_route(
     pool: PoolPg,
     clip_runtime: Handle,
+    storage: Storage,
     clients: ClientConnections,
     ffmpeg_progress_channels: FfmpegProgressChannels,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -103,30 +243,375 @@ pub fn clip_route(
         .and(warp::path!("clip"))
         .and(warp::path::end())
         .and(with_json_body::<ClipParameters>())
+        .and(with_user(pool.clone()))
+        .and(with(storage))
         .and(with(clients))
         .and(with(ffmpeg_progress_channels))
         .and(with_database(pool))
-        .map(
+        .and_then(
             move |parameters: ClipParameters,
+                  user: Option<User>,
+                  storage: Storage,
                   clients: ClientConnections,
                   ffmpeg_progress_channels: FfmpegProgressChannels,
                   database: Database| {
-                let uuid = Uuid::new_v4().to_string();
-                clip_runtime.spawn(clip(
-                    uuid.clone(),
-                    parameters.channel,
-                    [parameters.start_timestamp, parameters.end_timestamp],
-                    parameters.encode,
-                    database,
-                    clients,
-                    ffmpeg_progress_channels,
-                ));
-                uuid
+                let clip_runtime = clip_runtime.clone();
+                async move {
+                    // Check the request against what the channel advertises
+                    let manifest = match sources::get(&parameters.channel) {
+                        Some(source) => Some(source.manifest().await.map_err(ApiError::upstream)?),
+                        None => None,
+                    };
+                    let max_length_seconds = user
+                        .as_ref()
+                        .map_or(CONFIG.clips.max_length_seconds, |user| {
+                            user.max_length_seconds.max(0) as usize
+                        });
+                    let errors = parameters.validate(manifest.as_ref(), max_length_seconds);
+                    if !errors.is_empty() {
+                        Err(ApiError::invalid_fields(errors))?;
+                    }
+
+                    let uuid = Uuid::new_v4().to_string();
+                    clip_runtime.spawn(clip(
+                        uuid.clone(),
+                        parameters,
+                        user,
+                        database,
+                        storage,
+                        clients,
+                        ffmpeg_progress_channels,
+                    ));
+                    Ok::<_, warp::Rejection>(warp::reply::with_status(uuid, StatusCode::OK))
+                }
+            },
+        )
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// POST /recordings/{uuid}/cancel
+pub fn cancel_route(
+    pool: PoolPg,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("recordings" / String / "cancel"))
+        .and(warp::path::end())
+        .and(with_signed_in_user(pool.clone()))
+        .and(with_database(pool))
+        .and_then(
+            |uuid: String, user: User, mut database: Database| async move {
+                let recording = database
+                    .get_recording(&uuid)
+                    .map_err(ApiError::internal)?
+                    .ok_or_else(|| ApiError::not_found(format!("no recording {uuid}")))?;
+                authorize_owner(Some(&user), &recording)?;
+                if !cancel_clip(&uuid).await {
+                    Err(ApiError::conflict(format!(
+                        "{uuid} isn't queued or running"
+                    )))?;
+                }
+                Ok::<_, warp::Rejection>(warp::reply::with_status(
+                    "cancelling".to_string(),
+                    StatusCode::OK,
+                ))
+            },
+        )
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// POST /recordings/{uuid}/retry
+pub fn retry_route(
+    pool: PoolPg,
+    clip_runtime: Handle,
+    storage: Storage,
+    clients: ClientConnections,
+    ffmpeg_progress_channels: FfmpegProgressChannels,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("recordings" / String / "retry"))
+        .and(warp::path::end())
+        .and(with_signed_in_user(pool.clone()))
+        .and(with(storage))
+        .and(with(clients))
+        .and(with(ffmpeg_progress_channels))
+        .and(with_database(pool))
+        .and_then(
+            move |uuid: String,
+                  user: User,
+                  storage: Storage,
+                  clients: ClientConnections,
+                  ffmpeg_progress_channels: FfmpegProgressChannels,
+                  mut database: Database| {
+                let clip_runtime = clip_runtime.clone();
+                async move {
+                    let recording = database
+                        .get_recording(&uuid)
+                        .map_err(ApiError::internal)?
+                        .ok_or_else(|| ApiError::not_found(format!("no recording {uuid}")))?;
+                    authorize_owner(Some(&user), &recording)?;
+                    let failed = Stage::from_row(recording.stage)
+                        .filter(|stage| stage.is_failure())
+                        .ok_or_else(|| ApiError::conflict(format!("{uuid} hasn't failed")))?;
+                    // Claim the clip so concurrent retries don't run it twice
+                    if !database
+                        .set_stage_if(&uuid, failed, Stage::WaitingQueue)
+                        .map_err(ApiError::internal)?
+                    {
+                        Err(ApiError::conflict(format!(
+                            "{uuid} is already being retried"
+                        )))?;
+                    }
+
+                    clip_runtime.spawn(async move {
+                        if let Err(e) = retry_clip(
+                            recording,
+                            database,
+                            storage,
+                            clients,
+                            ffmpeg_progress_channels,
+                        )
+                        .await
+                        {
+                            error!("{uuid}: failed to retry: {e:?}");
+                        }
+                    });
+                    Ok::<_, warp::Rejection>(warp::reply::with_status(
+                        "retrying".to_string(),
+                        StatusCode::OK,
+                    ))
+                }
             },
         )
+        .with(warp::cors())
         .with(warp::log::custom(get_warp_logger))
 }
 
+/// DELETE /recordings/{uuid}
+pub fn delete_recording(
+    pool: PoolPg,
+    storage: Storage,
+    clients: ClientConnections,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(warp::path!("recordings" / String))
+        .and(warp::path::end())
+        .and(with_permission(pool.clone(), Permission::Delete))
+        .and(with(storage))
+        .and(with(clients))
+        .and(with_database(pool))
+        .and_then(
+            |uuid: String,
+             user: User,
+             storage: Storage,
+             clients: ClientConnections,
+             mut database: Database| async move {
+                let recording = database
+                    .get_recording(&uuid)
+                    .map_err(ApiError::internal)?
+                    .ok_or_else(|| ApiError::not_found(format!("no recording {uuid}")))?;
+                authorize_owner(Some(&user), &recording)?;
+                if !Stage::from_row(recording.stage).is_some_and(Stage::is_finished) {
+                    Err(ApiError::conflict(format!(
+                        "{uuid} is still being clipped, cancel it first"
+                    )))?;
+                }
+
+                // The row goes last so a failure can be retried
+                storage
+                    .delete(&clip_key(&recording.uuid, &recording.output_format))
+                    .await
+                    .map_err(ApiError::internal)?;
+                remove_clip_files(&uuid).await.map_err(ApiError::internal)?;
+                database
+                    .delete_recording(recording.id)
+                    .map_err(ApiError::internal)?;
+                if let Err(e) = alert_clients_of_deletion(clients, &uuid).await {
+                    error!("{uuid}: failed to announce deletion: {e:?}");
+                }
+                Ok::<_, warp::Rejection>(warp::reply::with_status(
+                    "deleted".to_string(),
+                    StatusCode::OK,
+                ))
+            },
+        )
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// GET /recordings/{uuid}/download (as an attachment) and
+/// GET /recordings/{uuid}/media (inline, for playback)
+pub fn recording_file(
+    pool: PoolPg,
+    storage: Storage,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("recordings" / String / String))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        // Turns anonymous callers away unless `[auth] allow_anonymous` is set
+        .and(with_user(pool.clone()))
+        .and(with_database(pool))
+        .and(with(storage))
+        .and_then(
+            |uuid: String,
+             kind: String,
+             range: Option<String>,
+             if_none_match: Option<String>,
+             _: Option<User>,
+             mut database: Database,
+             storage: Storage| async move {
+                let disposition = match kind.as_str() {
+                    "download" => "attachment",
+                    "media" => "inline",
+                    _ => Err(warp::reject::not_found())?,
+                };
+                let recording = database
+                    .get_recording(&uuid)
+                    .map_err(ApiError::internal)?
+                    .filter(|recording| recording.stage == Stage::Complete as i32)
+                    .ok_or_else(|| ApiError::not_found(format!("no finished recording {uuid}")))?;
+                serve_recording(
+                    &recording,
+                    disposition,
+                    range.as_deref(),
+                    if_none_match.as_deref(),
+                    &storage,
+                )
+                .await
+                .map_err(|e| match e.downcast_ref::<ObjectNotFound>() {
+                    Some(not_found) => warp::reject::custom(ApiError::not_found(not_found)),
+                    None => warp::reject::custom(ApiError::internal(e)),
+                })
+            },
+        )
+        .with(warp::cors())
+        .with(warp::log::custom(get_warp_logger))
+}
+
+/// Stream a recording's result from storage, honouring `Range` and `If-None-Match`
+async fn serve_recording(
+    recording: &Recording,
+    disposition: &str,
+    range: Option<&str>,
+    if_none_match: Option<&str>,
+    storage: &Storage,
+) -> anyhow::Result<Response<Body>> {
+    let local;
+    let (storage, key): (&dyn StorageBackend, _) = if recording.uploaded {
+        let key = clip_key(&recording.uuid, &recording.output_format);
+        (storage.as_ref(), key)
+    } else {
+        // Results of users who can't upload are only in the temp directory
+        local = LocalDirectory::new(PathBuf::from(TEMP_DIRECTORY));
+        let path = output_path(&recording.uuid, &recording.output_format);
+        let key = path
+            .strip_prefix(TEMP_DIRECTORY)?
+            .to_string_lossy()
+            .to_string();
+        (&local, key)
+    };
+    let size = storage
+        .size(&key)
+        .await?
+        .ok_or_else(|| ObjectNotFound(key.clone()))?;
+    // Results are never modified after upload
+    let etag = format!("\"{}-{size}\"", recording.uuid.trim());
+    let content_type = OutputFormat::from_extension(&recording.output_format)
+        .map_or("application/octet-stream", OutputFormat::mime_type);
+    let file_name = format!(
+        "{channel} {start} to {end}.{extension}",
+        channel = recording.channel,
+        start = recording.rec_start.format("%Y-%m-%d %H.%M.%S"),
+        end = recording.rec_end.format("%H.%M.%S"),
+        extension = recording.output_format
+    )
+    .replace(['"', '\\', '/'], "_");
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(
+            header::CACHE_CONTROL,
+            "private, max-age=31536000, immutable",
+        );
+    // `If-None-Match` compares weakly, so `W/` prefixes are ignored
+    if if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    }) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?);
+    }
+
+    let response = response.header(header::CONTENT_TYPE, content_type).header(
+        header::CONTENT_DISPOSITION,
+        format!("{disposition}; filename=\"{file_name}\""),
+    );
+    let (status, (start, end)) = match range.map(|range| parse_range(range, size)) {
+        None | Some(RangeRequest::Ignored) => (StatusCode::OK, (0, size.saturating_sub(1))),
+        Some(RangeRequest::Satisfiable(start, end)) => (StatusCode::PARTIAL_CONTENT, (start, end)),
+        Some(RangeRequest::Unsatisfiable) => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())?)
+        }
+    };
+    if size == 0 {
+        return Ok(response.status(StatusCode::OK).body(Body::empty())?);
+    }
+
+    let mut response = response
+        .status(status)
+        .header(header::CONTENT_LENGTH, end - start + 1);
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}"));
+    }
+    let body = storage.read(&key, (start, end)).await?;
+    Ok(response.body(Body::wrap_stream(body))?)
+}
+
+enum RangeRequest {
+    /// Serve the whole object (no range, or a form we don't support)
+    Ignored,
+    /// Inclusive byte range
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single-range `Range` header (`bytes=0-99`, `bytes=100-`, `bytes=-100`)
+fn parse_range(range: &str, size: u64) -> RangeRequest {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return RangeRequest::Ignored;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return RangeRequest::Ignored,
+        },
+        (start, end) => match (start.parse::<u64>(), end) {
+            (Ok(start), "") => (start, size.saturating_sub(1)),
+            (Ok(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => (start, end.min(size.saturating_sub(1))),
+                _ => return RangeRequest::Ignored,
+            },
+            _ => return RangeRequest::Ignored,
+        },
+    };
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable(start, end)
+}
+
 /// POST /ffmpeg-progress
 pub fn ffmpeg_progress(
     ffmpeg_progress_channels: FfmpegProgressChannels,
@@ -148,7 +633,7 @@ pub fn ffmpeg_progress(
                 }
                 ffmpeg_progress_update_handler(body, uuid, ffmpeg_progress_channels)
                     .await
-                    .map_err(|e| warp::reject::custom(ServerError::new(e)))
+                    .map_err(|e| warp::reject::custom(ApiError::internal(e)))
             },
         )
         .with(warp::log::custom(get_warp_logger))
@@ -164,10 +649,14 @@ pub fn websocket_route(
         .and(warp::path::end())
         .and(warp::ws())
         /* State */
+        .and(with_user(pool.clone()))
         .and(with(clients))
         .and(with_database(pool))
         .map(
-            |ws: warp::ws::Ws, clients: ClientConnections, database: Database| {
+            |ws: warp::ws::Ws,
+             user: Option<User>,
+             clients: ClientConnections,
+             database: Database| {
                 ws.on_upgrade(move |socket| {
                     handle_connection(
                         socket,
@@ -176,6 +665,7 @@ pub fn websocket_route(
                         on_disconnect,
                         on_message,
                         /* State */
+                        user,
                         clients,
                         database,
                     )
@@ -183,3 +673,453 @@ pub fn websocket_route(
             },
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        auth::TokenScope,
+        database::{
+            test::{pool as test_pool, unreachable_pool},
+            NewUser, RecordingUpdate,
+        },
+        error::recover,
+        storage::{self, StorageConfig},
+    };
+
+    use std::convert::Infallible;
+
+    use chrono::Utc;
+    use diesel::prelude::*;
+    use warp::{http::Method, test::request};
+
+    const CLIP_BODY: &str = r#"{"start_timestamp": 0, "end_timestamp": 10, "channel": "none"}"#;
+
+    /// The routes checking callers, as `main` serves them
+    async fn routes(
+        pool: PoolPg,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone + 'static {
+        let storage = storage::connect(&StorageConfig::Local {
+            directory: std::env::temp_dir().join("bbcd-test-storage"),
+        })
+        .await
+        .unwrap();
+        let clients = ClientConnections::default();
+        let ffmpeg_progress_channels = FfmpegProgressChannels::default();
+        list_recordings(pool.clone())
+            .or(sources_health(pool.clone()))
+            .or(recording_file(pool.clone(), storage.clone()))
+            .or(delete_recording(
+                pool.clone(),
+                storage.clone(),
+                clients.clone(),
+            ))
+            .or(cancel_route(pool.clone()))
+            .or(retry_route(
+                pool.clone(),
+                Handle::current(),
+                storage.clone(),
+                clients.clone(),
+                ffmpeg_progress_channels.clone(),
+            ))
+            .or(clip_route(
+                pool,
+                Handle::current(),
+                storage,
+                clients,
+                ffmpeg_progress_channels,
+            ))
+            .recover(recover)
+    }
+
+    /// Status of a request with an optional `Authorization` header
+    async fn status(
+        routes: &(impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone + 'static),
+        method: Method,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> StatusCode {
+        let mut request = request().method(method.as_str()).path(path);
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        if path == "/clip" {
+            request = request.body(CLIP_BODY);
+        }
+        request.reply(routes).await.status()
+    }
+
+    /// Create a user with an API token of `scope`, returning the user and its `Authorization`
+    fn create_user(
+        database: &mut Database,
+        configure: impl FnOnce(&mut User),
+        scope: TokenScope,
+    ) -> (User, String) {
+        let user = database
+            .create_user(&NewUser {
+                username: Uuid::new_v4().to_string()[..32].to_string(),
+                password_hash: None,
+                superuser: false,
+            })
+            .unwrap();
+        let mut configured = user.clone();
+        configure(&mut configured);
+        {
+            use crate::schema::users::dsl::*;
+            diesel::update(users.find(user.id))
+                .set((
+                    can_upload.eq(configured.can_upload),
+                    can_delete.eq(configured.can_delete),
+                    superuser.eq(configured.superuser),
+                ))
+                .execute(&mut database.connection)
+                .unwrap();
+        }
+        let token = generate_token();
+        database
+            .create_api_token(&NewApiToken {
+                user_id: user.id,
+                name: "test".to_string(),
+                token_hash: hash_token(&token),
+                scope: scope.as_str().to_string(),
+                expires_at: None,
+            })
+            .unwrap();
+        (configured, format!("Bearer {token}"))
+    }
+
+    fn create_recording(database: &mut Database, user_id: UserId, stage: Stage) -> String {
+        let now = Utc::now().naive_utc();
+        let uuid = Uuid::new_v4().to_string();
+        database
+            .create_recording(&RecordingUpdate {
+                user_id: Some(user_id),
+                uuid: uuid.clone(),
+                rec_start: now,
+                rec_end: now,
+                status: String::new(),
+                short_status: String::new(),
+                stage: stage as i32,
+                channel: "bbc_one_hd".to_string(),
+                output_format: "mp4".to_string(),
+                encode_profile: "copy".to_string(),
+                encode_options: String::new(),
+                quality: "best".to_string(),
+                uploaded: true,
+            })
+            .unwrap();
+        uuid
+    }
+
+    #[tokio::test]
+    async fn anonymous_callers_must_sign_in() {
+        // Rejected before reaching the database
+        let routes = routes(unreachable_pool()).await;
+        for (method, path) in [
+            (Method::GET, "/sources/health"),
+            (Method::POST, "/recordings/x/cancel"),
+            (Method::POST, "/recordings/x/retry"),
+            (Method::DELETE, "/recordings/x"),
+        ] {
+            let response = request()
+                .method(method.as_str())
+                .path(path)
+                .reply(&routes)
+                .await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{method} {path}"
+            );
+            assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_credentials_are_rejected() {
+        let routes = routes(unreachable_pool()).await;
+        for (method, path) in [
+            (Method::GET, "/list-recordings"),
+            (Method::GET, "/recordings/x/media"),
+            (Method::GET, "/sources/health"),
+            (Method::POST, "/clip"),
+            (Method::POST, "/recordings/x/cancel"),
+            (Method::POST, "/recordings/x/retry"),
+            (Method::DELETE, "/recordings/x"),
+        ] {
+            assert_eq!(
+                status(&routes, method.clone(), path, Some("Digest abc")).await,
+                StatusCode::UNAUTHORIZED,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn unknown_tokens_are_rejected() {
+        let routes = routes(test_pool()).await;
+        let authorization = format!("Bearer {}", generate_token());
+        for (method, path) in [
+            (Method::GET, "/list-recordings"),
+            (Method::POST, "/clip"),
+            (Method::DELETE, "/recordings/x"),
+        ] {
+            assert_eq!(
+                status(&routes, method.clone(), path, Some(&authorization)).await,
+                StatusCode::UNAUTHORIZED,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn users_without_upload_permission_can_clip() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (_, without_upload) = create_user(
+            &mut database,
+            |user| user.can_upload = false,
+            TokenScope::Full,
+        );
+        let (_, with_upload) = create_user(&mut database, |_| {}, TokenScope::Clip);
+
+        // Both get as far as validation, where the unknown channel fails
+        let routes = routes(pool).await;
+        for authorization in [&without_upload, &with_upload] {
+            assert_eq!(
+                status(&routes, Method::POST, "/clip", Some(authorization)).await,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn results_that_werent_uploaded_are_served_from_the_temp_directory() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (user, authorization) = create_user(
+            &mut database,
+            |user| user.can_upload = false,
+            TokenScope::Full,
+        );
+        let uuid = create_recording(&mut database, user.id, Stage::Complete);
+        {
+            use crate::schema::recordings::dsl;
+            diesel::update(dsl::recordings.filter(dsl::uuid.eq(&uuid)))
+                .set(dsl::uploaded.eq(false))
+                .execute(&mut database.connection)
+                .unwrap();
+        }
+        let path = output_path(&uuid, "mp4");
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&path, b"clip").await.unwrap();
+
+        let routes = routes(pool).await;
+        let response = request()
+            .path(&format!("/recordings/{uuid}/download"))
+            .header("authorization", &authorization)
+            .reply(&routes)
+            .await;
+        remove_clip_files(&uuid).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"clip");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn cancelling_and_retrying_need_ownership() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (owner, owner_authorization) = create_user(&mut database, |_| {}, TokenScope::Clip);
+        let (_, other) = create_user(&mut database, |_| {}, TokenScope::Full);
+        let (_, superuser) = create_user(
+            &mut database,
+            |user| user.superuser = true,
+            TokenScope::Full,
+        );
+        let uuid = create_recording(&mut database, owner.id, Stage::Complete);
+
+        let routes = routes(pool).await;
+        for action in ["cancel", "retry"] {
+            let path = format!("/recordings/{uuid}/{action}");
+            assert_eq!(
+                status(&routes, Method::POST, &path, Some(&other)).await,
+                StatusCode::FORBIDDEN,
+                "{action}"
+            );
+            // Allowed, but the clip is neither running nor failed
+            for authorization in [&owner_authorization, &superuser] {
+                assert_eq!(
+                    status(&routes, Method::POST, &path, Some(authorization)).await,
+                    StatusCode::CONFLICT,
+                    "{action}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn retries_claim_the_clip_once() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (owner, authorization) = create_user(&mut database, |_| {}, TokenScope::Full);
+        let uuid = create_recording(&mut database, owner.id, Stage::DownloadingFailed);
+
+        // A concurrent retry got there first
+        assert!(database
+            .set_stage_if(&uuid, Stage::DownloadingFailed, Stage::WaitingQueue)
+            .unwrap());
+        assert!(!database
+            .set_stage_if(&uuid, Stage::DownloadingFailed, Stage::WaitingQueue)
+            .unwrap());
+
+        let routes = routes(pool).await;
+        let path = format!("/recordings/{uuid}/retry");
+        assert_eq!(
+            status(&routes, Method::POST, &path, Some(&authorization)).await,
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn deleting_needs_permission_and_ownership() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (owner, without_delete) = create_user(&mut database, |_| {}, TokenScope::Full);
+        let can_delete = |user: &mut User| user.can_delete = true;
+        let (_, other) = create_user(&mut database, can_delete, TokenScope::Full);
+        let uuid = create_recording(&mut database, owner.id, Stage::Cancelled);
+        let path = format!("/recordings/{uuid}");
+
+        let routes = routes(pool).await;
+        assert_eq!(
+            status(&routes, Method::DELETE, &path, Some(&without_delete)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&routes, Method::DELETE, &path, Some(&other)).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let (deleter, clip_scoped) = create_user(&mut database, can_delete, TokenScope::Clip);
+        let uuid = create_recording(&mut database, deleter.id, Stage::Cancelled);
+        let path = format!("/recordings/{uuid}");
+        assert_eq!(
+            status(&routes, Method::DELETE, &path, Some(&clip_scoped)).await,
+            StatusCode::FORBIDDEN
+        );
+        let (_, superuser) = create_user(
+            &mut database,
+            |user| user.superuser = true,
+            TokenScope::Full,
+        );
+        assert_eq!(
+            status(&routes, Method::DELETE, &path, Some(&superuser)).await,
+            StatusCode::OK
+        );
+        assert!(database.get_recording(&uuid).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn results_missing_from_storage_are_not_found() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (user, authorization) = create_user(&mut database, |_| {}, TokenScope::Full);
+        let uuid = create_recording(&mut database, user.id, Stage::Complete);
+
+        let routes = routes(pool).await;
+        let path = format!("/recordings/{uuid}/media");
+        assert_eq!(
+            status(&routes, Method::GET, &path, Some(&authorization)).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn weak_validators_match() {
+        let directory = std::env::temp_dir().join(format!("bbcd-test-{}", Uuid::new_v4()));
+        let storage = storage::connect(&StorageConfig::Local {
+            directory: directory.clone(),
+        })
+        .await
+        .unwrap();
+        let now = Utc::now().naive_utc();
+        let recording = Recording {
+            id: 1,
+            user_id: None,
+            uuid: Uuid::new_v4().to_string(),
+            rec_start: now,
+            rec_end: now,
+            status: String::new(),
+            short_status: String::new(),
+            stage: Stage::Complete as i32,
+            channel: "bbc_one_hd".to_string(),
+            output_format: "mp4".to_string(),
+            encode_profile: "copy".to_string(),
+            encode_options: String::new(),
+            quality: "best".to_string(),
+            uploaded: true,
+        };
+        tokio::fs::write(directory.join(clip_key(&recording.uuid, "mp4")), b"clip")
+            .await
+            .unwrap();
+
+        let etag = format!("\"{}-4\"", recording.uuid);
+        for (if_none_match, expected) in [
+            (etag.clone(), StatusCode::NOT_MODIFIED),
+            (format!("W/{etag}"), StatusCode::NOT_MODIFIED),
+            (format!("\"other\", W/{etag}"), StatusCode::NOT_MODIFIED),
+            ("W/\"other\"".to_string(), StatusCode::OK),
+        ] {
+            let response =
+                serve_recording(&recording, "inline", None, Some(&if_none_match), &storage)
+                    .await
+                    .unwrap();
+            assert_eq!(response.status(), expected, "{if_none_match}");
+        }
+        let _ = tokio::fs::remove_dir_all(&directory).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database in TEST_DATABASE_URL"]
+    async fn source_health_is_for_superusers() {
+        let pool = test_pool();
+        let mut database = Database::connect(&pool).unwrap();
+        let (_, user) = create_user(
+            &mut database,
+            |user| user.can_delete = true,
+            TokenScope::Full,
+        );
+        let (_, superuser) = create_user(
+            &mut database,
+            |user| user.superuser = true,
+            TokenScope::Full,
+        );
+        let (_, clip_scoped) = create_user(
+            &mut database,
+            |user| user.superuser = true,
+            TokenScope::Clip,
+        );
+
+        let routes = routes(pool).await;
+        for (authorization, expected) in [
+            (&user, StatusCode::FORBIDDEN),
+            (&clip_scoped, StatusCode::FORBIDDEN),
+            (&superuser, StatusCode::OK),
+        ] {
+            assert_eq!(
+                status(&routes, Method::GET, "/sources/health", Some(authorization)).await,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Health of the CDN origins sources are fetched from
+
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Weight of the latest request in the moving averages
+const SMOOTHING: f64 = 0.2;
+
+lazy_static! {
+    static ref ORIGINS: RwLock<HashMap<String, OriginHealth>> = RwLock::new(HashMap::new());
+}
+
+/// Request statistics for one origin (the scheme and host of a URL)
+#[derive(Serialize, Clone, Debug)]
+pub struct OriginHealth {
+    pub origin: String,
+    pub successes: u64,
+    pub failures: u64,
+    /// Moving average of successful requests, from `0` to `1`
+    pub success_rate: f64,
+    /// Moving average of the time taken by successful requests
+    pub latency_ms: Option<f64>,
+    pub last_failure: Option<DateTime<Utc>>,
+}
+impl OriginHealth {
+    fn new(origin: String) -> Self {
+        Self {
+            origin,
+            successes: 0,
+            failures: 0,
+            success_rate: 1.,
+            latency_ms: None,
+            last_failure: None,
+        }
+    }
+
+    /// Update the moving averages with the outcome of a request
+    fn record(&mut self, success: bool, elapsed: Duration) {
+        let outcome = if success { 1. } else { 0. };
+        self.success_rate += SMOOTHING * (outcome - self.success_rate);
+        if success {
+            self.successes += 1;
+            let latency_ms = elapsed.as_secs_f64() * 1000.;
+            self.latency_ms = Some(match self.latency_ms {
+                Some(average) => average + SMOOTHING * (latency_ms - average),
+                None => latency_ms,
+            });
+        } else {
+            self.failures += 1;
+            self.last_failure = Some(Utc::now());
+        }
+    }
+}
+
+/// Scheme and host of a URL
+fn origin(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |idx| idx + 3);
+    match url[host_start..].find('/') {
+        Some(idx) => &url[..host_start + idx],
+        None => url,
+    }
+}
+
+/// Record the outcome of a request to `url`
+pub fn record(url: &str, success: bool, elapsed: Duration) {
+    let origin = origin(url);
+    ORIGINS
+        .write()
+        .expect("origin health lock poisoned")
+        .entry(origin.to_string())
+        .or_insert_with(|| OriginHealth::new(origin.to_string()))
+        .record(success, elapsed);
+}
+
+/// URLs ordered from the healthiest origin to the least healthy.
+/// Origins without requests yet count as healthy and as fast as the others on average, and
+/// ties keep their original order.
+pub fn rank(urls: &[String]) -> Vec<&String> {
+    rank_by(&ORIGINS.read().expect("origin health lock poisoned"), urls)
+}
+
+fn rank_by<'a>(origins: &HashMap<String, OriginHealth>, urls: &'a [String]) -> Vec<&'a String> {
+    let health = |url: &String| origins.get(origin(url));
+    let latencies = urls
+        .iter()
+        .filter_map(|url| health(url)?.latency_ms)
+        .collect::<Vec<_>>();
+    let mean_latency_ms = latencies.iter().sum::<f64>() / latencies.len().max(1) as f64;
+    let mut ranked = urls
+        .iter()
+        .map(|url| {
+            let health = health(url);
+            let success_rate = health.map_or(1., |health| health.success_rate);
+            let latency_ms = health
+                .and_then(|health| health.latency_ms)
+                .unwrap_or(mean_latency_ms);
+            (url, success_rate, latency_ms)
+        })
+        .collect::<Vec<_>>();
+    // Success rates within a few percent of each other are compared by latency
+    ranked.sort_by(|(_, a_rate, a_latency), (_, b_rate, b_latency)| {
+        ((b_rate * 20.).round())
+            .total_cmp(&(a_rate * 20.).round())
+            .then(a_latency.total_cmp(b_latency))
+    });
+    ranked.into_iter().map(|(url, ..)| url).collect()
+}
+
+/// Statistics of every origin requested so far
+pub fn snapshot() -> Vec<OriginHealth> {
+    let mut origins = ORIGINS
+        .read()
+        .expect("origin health lock poisoned")
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    origins.sort_by(|a, b| a.origin.cmp(&b.origin));
+    origins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(stats: &[(&str, &[(bool, u64)])]) -> HashMap<String, OriginHealth> {
+        stats
+            .iter()
+            .map(|(origin, requests)| {
+                let mut health = OriginHealth::new(origin.to_string());
+                for &(success, ms) in *requests {
+                    health.record(success, Duration::from_millis(ms));
+                }
+                (origin.to_string(), health)
+            })
+            .collect()
+    }
+
+    fn urls(hosts: &[&str]) -> Vec<String> {
+        hosts
+            .iter()
+            .map(|host| format!("https://{host}/live/manifest.mpd"))
+            .collect()
+    }
+
+    fn ranked_hosts(origins: &HashMap<String, OriginHealth>, hosts: &[&str]) -> Vec<String> {
+        rank_by(origins, &urls(hosts))
+            .into_iter()
+            .map(|url| origin(url).trim_start_matches("https://").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn origins_are_scheme_and_host() {
+        assert_eq!(origin("https://a.example/live/1.m4s"), "https://a.example");
+        assert_eq!(origin("https://a.example"), "https://a.example");
+    }
+
+    #[test]
+    fn moving_averages_follow_requests() {
+        let mut health = OriginHealth::new("https://a.example".to_string());
+        health.record(true, Duration::from_millis(100));
+        assert_eq!(health.latency_ms, Some(100.));
+        assert_eq!(health.success_rate, 1.);
+
+        health.record(true, Duration::from_millis(200));
+        assert!((health.latency_ms.unwrap() - 120.).abs() < 1e-9);
+        health.record(false, Duration::from_millis(5000));
+        // Failures don't count towards the latency
+        assert!((health.latency_ms.unwrap() - 120.).abs() < 1e-9);
+        assert!((health.success_rate - 0.8).abs() < 1e-9);
+        assert_eq!((health.successes, health.failures), (2, 1));
+        assert!(health.last_failure.is_some());
+    }
+
+    #[test]
+    fn healthy_origins_come_first() {
+        let origins = origins(&[
+            ("https://failing.example", &[(false, 0), (false, 0)]),
+            ("https://slow.example", &[(true, 400)]),
+            ("https://fast.example", &[(true, 100)]),
+        ]);
+        assert_eq!(
+            ranked_hosts(
+                &origins,
+                &["failing.example", "slow.example", "fast.example"]
+            ),
+            ["fast.example", "slow.example", "failing.example"]
+        );
+    }
+
+    #[test]
+    fn unseen_origins_rank_as_average() {
+        let origins = origins(&[
+            ("https://slow.example", &[(true, 400)]),
+            ("https://fast.example", &[(true, 100)]),
+        ]);
+        assert_eq!(
+            ranked_hosts(&origins, &["new.example", "slow.example", "fast.example"]),
+            ["fast.example", "new.example", "slow.example"]
+        );
+        // Without anything to compare against, the original order is kept
+        assert_eq!(
+            ranked_hosts(&HashMap::new(), &["b.example", "a.example"]),
+            ["b.example", "a.example"]
+        );
+    }
+}
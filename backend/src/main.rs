@@ -1,26 +1,41 @@
 //! The backend for BBCD!!!
 
+pub mod auth;
 pub mod clip;
+pub mod config;
 pub mod consts;
 pub mod database;
+pub mod dvr;
+pub mod error;
 pub mod filters;
+pub mod health;
+pub mod manifest;
 pub mod schema;
+pub mod segment_cache;
+pub mod sources;
+pub mod storage;
 pub mod tree;
 pub mod websocket_callbacks;
 pub mod websocket_connection;
 
 use crate::{
-    filters::{clip_route, list_recordings, root_route, websocket_route},
+    config::CONFIG,
+    error::recover,
+    filters::{
+        cancel_route, clip_route, create_token, delete_recording, list_recordings, list_sources,
+        list_tokens, recording_file, retry_route, revoke_token, root_route, sources_health,
+        websocket_route,
+    },
     tree::init_logger,
 };
 
 use std::thread;
 
-use anyhow::Result;
-use clip::FfmpegProgressChannels;
+use anyhow::{Context as _, Result};
+use clip::{clean_temp_directory, resume_clips, FfmpegProgressChannels};
 use dotenvy::dotenv;
 use filters::ffmpeg_progress;
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 use tokio::runtime::Runtime;
 use warp::Filter as _;
 use websocket_connection::ClientConnections;
@@ -33,6 +48,23 @@ async fn main() -> Result<()> {
     init_logger();
     debug!("hello from the bbcd backend!");
     trace!("trace enabled!");
+    lazy_static::initialize(&CONFIG);
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(("add-user", args)) = args
+        .split_first()
+        .map(|(command, args)| (command.as_str(), args))
+    {
+        let pool = database::establish_connection()?;
+        return auth::add_user_command(
+            database::Database {
+                connection: pool.get()?,
+            },
+            args,
+        );
+    }
+
+    sources::initialize().context("failed to load sources")?;
 
     let (clip_shutdown_tx, clip_shutdown_rx) = tokio::sync::oneshot::channel();
     let (clip_handle_tx, clip_handle_rx) = std::sync::mpsc::channel();
@@ -61,19 +93,61 @@ async fn main() -> Result<()> {
         let pool =
             database::establish_connection().expect("failed to establish database connection");
 
+        let storage = runtime
+            .block_on(storage::connect(&CONFIG.storage))
+            .expect("failed to set up storage");
+
         let clients = ClientConnections::default();
         let ffmpeg_progress_channels = FfmpegProgressChannels::default();
 
+        clip_runtime.spawn(clean_temp_directory());
+        clip_runtime.spawn(sources::watch());
+        clip_runtime.spawn(dvr::record_all());
+        clip_runtime.spawn({
+            let resume = resume_clips(
+                pool.clone(),
+                storage.clone(),
+                clients.clone(),
+                ffmpeg_progress_channels.clone(),
+            );
+            async move {
+                if let Err(e) = resume.await {
+                    error!("failed to resume unfinished clips: {e:?}");
+                }
+            }
+        });
+
         let routes = root_route()
             .or(websocket_route(pool.clone(), clients.clone()))
             .or(list_recordings(pool.clone()))
+            .or(list_sources())
+            .or(sources_health(pool.clone()))
+            .or(create_token(pool.clone()))
+            .or(list_tokens(pool.clone()))
+            .or(revoke_token(pool.clone()))
+            .or(recording_file(pool.clone(), storage.clone()))
+            .or(delete_recording(
+                pool.clone(),
+                storage.clone(),
+                clients.clone(),
+            ))
+            .or(cancel_route(pool.clone()))
+            .or(retry_route(
+                pool.clone(),
+                clip_runtime.clone(),
+                storage.clone(),
+                clients.clone(),
+                ffmpeg_progress_channels.clone(),
+            ))
             .or(clip_route(
                 pool.clone(),
                 clip_runtime,
+                storage.clone(),
                 clients.clone(),
                 ffmpeg_progress_channels.clone(),
             ))
-            .or(ffmpeg_progress(ffmpeg_progress_channels.clone()));
+            .or(ffmpeg_progress(ffmpeg_progress_channels.clone()))
+            .recover(recover);
 
         runtime.block_on(async move {
             info!("running on port {PORT}!");
@@ -0,0 +1,841 @@
+//! Parsing for a source's live DASH manifest (MPD)
+
+use crate::{clip::DOWNLOAD_CLIENT, consts::MANIFEST_FILENAME, health};
+
+use std::{fmt::Display, str::FromStr, time::Instant};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{trace, warn};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Kind of media carried by a representation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Video,
+    Audio,
+}
+impl ContentType {
+    /// Name used for the files of this content type
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Video => "video",
+            Self::Audio => "audio",
+        }
+    }
+}
+
+/// A `<SegmentTemplate>` using `$Number$` addressing, with the period start folded in
+#[derive(Clone, Debug)]
+pub struct SegmentTemplate {
+    pub timescale: u64,
+    pub duration: u64,
+    pub start_number: usize,
+    pub initialization: String,
+    pub media: String,
+    /// Offset of the period from the availability start time
+    pub period_start: TimeDelta,
+}
+
+/// A single `<Representation>` with its effective segment template
+#[derive(Clone, Debug)]
+pub struct Representation {
+    pub id: String,
+    pub content_type: ContentType,
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub language: Option<String>,
+    /// DASH role of the adaptation set (e.g. `main`, `description`)
+    pub role: Option<String>,
+    pub codecs: Option<String>,
+    pub template: SegmentTemplate,
+    /// Availability start time of the manifest this representation belongs to
+    pub availability_start_time: DateTime<Utc>,
+}
+impl Representation {
+    /// Quality label of a video representation (e.g. `1080p50`)
+    pub fn quality_label(&self) -> Option<String> {
+        let height = self.height?;
+        Some(match self.frame_rate {
+            Some(frame_rate) => format!("{height}p{}", frame_rate.round()),
+            None => format!("{height}p"),
+        })
+    }
+
+    /// Duration of a single segment in seconds
+    pub fn segment_duration(&self) -> f64 {
+        self.template.duration as f64 / self.template.timescale as f64
+    }
+
+    /// Seconds between the Unix epoch and the first segment (`startNumber`)
+    fn first_segment_start(&self) -> f64 {
+        self.availability_start_time.timestamp_millis() as f64 / 1000.
+            + self.template.period_start.num_milliseconds() as f64 / 1000.
+    }
+
+    /// Convert a Unix timestamp to a segment index (referred to in the manifest as $Number$)
+    pub fn segment_idx(&self, timestamp: usize) -> usize {
+        let elapsed = (timestamp as f64 - self.first_segment_start()).max(0.);
+        (elapsed / self.segment_duration()).floor() as usize + self.template.start_number
+    }
+
+    /// Unix timestamp at which the segment with the given index starts
+    pub fn segment_start(&self, segment_idx: usize) -> f64 {
+        let offset = segment_idx.saturating_sub(self.template.start_number);
+        self.first_segment_start() + offset as f64 * self.segment_duration()
+    }
+
+    /// URL of the initialization segment
+    pub fn init_url(&self, url_prefix: &str) -> Result<String> {
+        Ok(format!(
+            "{url_prefix}{}",
+            self.expand(&self.template.initialization, None)?
+        ))
+    }
+
+    /// URL of the media segment with the given index
+    pub fn segment_url(&self, url_prefix: &str, segment_idx: usize) -> Result<String> {
+        Ok(format!(
+            "{url_prefix}{}",
+            self.expand(&self.template.media, Some(segment_idx))?
+        ))
+    }
+
+    /// Substitute the template identifiers (`$RepresentationID$`, `$Number$`, `$Bandwidth$`)
+    fn expand(&self, template: &str, segment_idx: Option<usize>) -> Result<String> {
+        let mut result = String::with_capacity(template.len());
+        let mut parts = template.split('$');
+        result.push_str(parts.next().unwrap_or_default());
+        while let Some(identifier) = parts.next() {
+            let literal = parts
+                .next()
+                .with_context(|| anyhow!("unterminated identifier in template {template}"))?;
+            // Identifiers may carry a `%0<width>d` format tag
+            let (name, width) = match identifier.split_once('%') {
+                Some((name, format)) => (
+                    name,
+                    format
+                        .trim_start_matches('0')
+                        .trim_end_matches('d')
+                        .parse::<usize>()
+                        .unwrap_or(0),
+                ),
+                None => (identifier, 0),
+            };
+            match name {
+                "" => result.push('$'),
+                "RepresentationID" => result.push_str(&self.id),
+                "Bandwidth" => result.push_str(&format!("{:0width$}", self.bandwidth)),
+                "Number" => result.push_str(&format!(
+                    "{:0width$}",
+                    segment_idx.with_context(|| anyhow!("$Number$ in {template}"))?
+                )),
+                _ => bail!("unsupported template identifier ${identifier}$ in {template}"),
+            }
+            result.push_str(literal);
+        }
+        Ok(result)
+    }
+}
+
+/// The parts of a live manifest needed to address segments
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub availability_start_time: DateTime<Utc>,
+    pub time_shift_buffer_depth: Option<TimeDelta>,
+    pub representations: Vec<Representation>,
+}
+impl Manifest {
+    /// Fetch and parse the manifest under a source's URL prefix
+    pub async fn fetch(url_prefix: &str) -> Result<Self> {
+        let url = format!("{url_prefix}{MANIFEST_FILENAME}");
+        trace!("fetching manifest {url}");
+        let body = DOWNLOAD_CLIENT
+            .get(&url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| anyhow!("request to {url}"))?
+            .text()
+            .await
+            .with_context(|| anyhow!("reading manifest from {url}"))?;
+        Self::parse(&body).with_context(|| anyhow!("parsing manifest from {url}"))
+    }
+
+    /// Fetch the manifest from the first of a source's URL prefixes that serves it,
+    /// healthiest origin first
+    pub async fn fetch_any(url_prefixes: &[String]) -> Result<Self> {
+        let mut errors = vec![];
+        for url_prefix in health::rank(url_prefixes) {
+            let start = Instant::now();
+            let result = Self::fetch(url_prefix).await;
+            health::record(url_prefix, result.is_ok(), start.elapsed());
+            match result {
+                Ok(manifest) => return Ok(manifest),
+                Err(e) => {
+                    warn!("failed to fetch manifest from {url_prefix}: {e:?}");
+                    errors.push(e);
+                }
+            }
+        }
+        Err(errors
+            .pop()
+            .unwrap_or_else(|| anyhow!("source has no URL prefixes")))
+    }
+
+    /// Parse an MPD document
+    pub fn parse(xml: &str) -> Result<Self> {
+        let mpd: xml::Mpd = quick_xml::de::from_str(xml).context("malformed MPD")?;
+
+        let availability_start_time = match &mpd.availability_start_time {
+            Some(time) => DateTime::parse_from_rfc3339(time)
+                .with_context(|| anyhow!("bad availabilityStartTime {time}"))?
+                .to_utc(),
+            None => DateTime::UNIX_EPOCH,
+        };
+        let time_shift_buffer_depth = mpd
+            .time_shift_buffer_depth
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
+
+        // Live sources only carry a single period
+        let period = mpd.periods.first().context("manifest has no periods")?;
+        let period_start = period
+            .start
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?
+            .unwrap_or_default();
+
+        let mut representations = vec![];
+        for adaptation_set in &period.adaptation_sets {
+            for representation in &adaptation_set.representations {
+                let content_type = [
+                    adaptation_set.content_type.as_deref(),
+                    representation.mime_type.as_deref(),
+                    adaptation_set.mime_type.as_deref(),
+                ]
+                .into_iter()
+                .flatten()
+                .find_map(|kind| match kind.split('/').next() {
+                    Some("video") => Some(ContentType::Video),
+                    Some("audio") => Some(ContentType::Audio),
+                    _ => None,
+                });
+                let Some(content_type) = content_type else {
+                    trace!(
+                        "skipping representation {id} of unknown type",
+                        id = representation.id
+                    );
+                    continue;
+                };
+
+                let template = xml::SegmentTemplate::merge(
+                    adaptation_set.segment_template.as_ref(),
+                    representation.segment_template.as_ref(),
+                );
+                let template = SegmentTemplate {
+                    timescale: template.timescale.unwrap_or(1),
+                    duration: template.duration.with_context(|| {
+                        anyhow!(
+                            "representation {} has no segment duration",
+                            representation.id
+                        )
+                    })?,
+                    start_number: template.start_number.unwrap_or(1),
+                    initialization: template.initialization.with_context(|| {
+                        anyhow!("representation {} has no initialization", representation.id)
+                    })?,
+                    media: template.media.with_context(|| {
+                        anyhow!("representation {} has no media template", representation.id)
+                    })?,
+                    period_start,
+                };
+                if template.timescale == 0 || template.duration == 0 {
+                    bail!(
+                        "representation {} has a zero segment duration",
+                        representation.id
+                    );
+                }
+
+                let frame_rate = representation
+                    .frame_rate
+                    .as_deref()
+                    .or(adaptation_set.frame_rate.as_deref())
+                    .and_then(parse_frame_rate);
+                representations.push(Representation {
+                    id: representation.id.clone(),
+                    content_type,
+                    bandwidth: representation.bandwidth,
+                    width: representation.width,
+                    height: representation.height,
+                    frame_rate,
+                    language: adaptation_set.lang.clone(),
+                    role: adaptation_set.roles.first().map(|role| role.value.clone()),
+                    codecs: representation
+                        .codecs
+                        .clone()
+                        .or(adaptation_set.codecs.clone()),
+                    template,
+                    availability_start_time,
+                });
+            }
+        }
+
+        Ok(Self {
+            availability_start_time,
+            time_shift_buffer_depth,
+            representations,
+        })
+    }
+
+    /// Representations of a content type
+    pub fn representations(
+        &self,
+        content_type: ContentType,
+    ) -> impl Iterator<Item = &Representation> {
+        self.representations
+            .iter()
+            .filter(move |representation| representation.content_type == content_type)
+    }
+
+    /// The highest bandwidth representation of a content type, preferring the main role
+    pub fn best(&self, content_type: ContentType) -> Result<&Representation> {
+        self.representations(content_type)
+            .max_by_key(|representation| {
+                let main = matches!(representation.role.as_deref(), None | Some("main"));
+                (main, representation.bandwidth)
+            })
+            .with_context(|| anyhow!("manifest has no {} representations", content_type.name()))
+    }
+
+    /// Pick the representations to download for a quality
+    pub fn select(&self, quality: &Quality) -> Result<Vec<&Representation>> {
+        let selection = match quality {
+            Quality::AudioOnly => vec![self.best(ContentType::Audio)?],
+            Quality::Best => vec![
+                self.best(ContentType::Video)?,
+                self.best(ContentType::Audio)?,
+            ],
+            Quality::Video { height, frame_rate } => {
+                let video = self
+                    .representations(ContentType::Video)
+                    .filter(|representation| {
+                        representation.height == Some(*height)
+                            && frame_rate.is_none_or(|frame_rate| {
+                                representation.frame_rate.map(|rate| rate.round() as u32)
+                                    == Some(frame_rate)
+                            })
+                    })
+                    .max_by_key(|representation| representation.bandwidth);
+                match video {
+                    Some(video) => vec![video, self.best(ContentType::Audio)?],
+                    None => bail!(
+                        "quality {quality} is not available (available: {})",
+                        self.available_qualities().join(", ")
+                    ),
+                }
+            }
+            Quality::Representation(id) => {
+                let representation = self
+                    .representations
+                    .iter()
+                    .find(|representation| &representation.id == id)
+                    .with_context(|| {
+                        anyhow!(
+                            "representation {id} is not available (available: {})",
+                            self.representations
+                                .iter()
+                                .map(|representation| representation.id.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    })?;
+                // An explicit representation replaces the default of its content type
+                match representation.content_type {
+                    ContentType::Video => vec![representation, self.best(ContentType::Audio)?],
+                    ContentType::Audio => vec![self.best(ContentType::Video)?, representation],
+                }
+            }
+        };
+        Ok(selection)
+    }
+
+    /// Labels of the video qualities in the manifest (e.g. `1080p50`), best first
+    pub fn available_qualities(&self) -> Vec<String> {
+        let mut videos = self.representations(ContentType::Video).collect::<Vec<_>>();
+        videos.sort_by_key(|representation| std::cmp::Reverse(representation.bandwidth));
+        let mut labels = vec![];
+        for label in videos.into_iter().filter_map(Representation::quality_label) {
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+        labels
+    }
+}
+
+/// A requested video quality
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Quality {
+    /// The highest bandwidth video and audio
+    #[default]
+    Best,
+    /// A video height with an optional frame rate (`720p`, `1080p50`)
+    Video {
+        height: u32,
+        frame_rate: Option<u32>,
+    },
+    /// No video, only the best audio
+    AudioOnly,
+    /// An explicit representation ID from the manifest
+    Representation(String),
+}
+impl FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(quality: &str) -> Result<Self> {
+        let quality = quality.trim();
+        if quality.is_empty() || quality.eq_ignore_ascii_case("best") {
+            return Ok(Self::Best);
+        }
+        if quality.eq_ignore_ascii_case("audio-only") {
+            return Ok(Self::AudioOnly);
+        }
+        // Anything else, like `720p_main`, is taken for a representation ID
+        if let Some((height, frame_rate)) = quality.split_once('p') {
+            let frame_rate = match frame_rate {
+                "" => Some(None),
+                frame_rate => frame_rate.parse().ok().map(Some),
+            };
+            if let (Ok(height), Some(frame_rate)) = (height.parse(), frame_rate) {
+                return Ok(Self::Video { height, frame_rate });
+            }
+        }
+        Ok(Self::Representation(quality.to_string()))
+    }
+}
+impl<'de> Deserialize<'de> for Quality {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+impl Display for Quality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Best => write!(f, "best"),
+            Self::AudioOnly => write!(f, "audio-only"),
+            Self::Video {
+                height,
+                frame_rate: Some(frame_rate),
+            } => write!(f, "{height}p{frame_rate}"),
+            Self::Video { height, .. } => write!(f, "{height}p"),
+            Self::Representation(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+/// Parse an ISO 8601 duration as used by MPDs (e.g. `PT3.84S`, `P1DT6H`)
+pub fn parse_duration(duration: &str) -> Result<TimeDelta> {
+    let bad = || anyhow!("bad duration {duration}");
+    let rest = duration.strip_prefix('P').ok_or_else(bad)?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+
+    let mut seconds = 0.;
+    for (part, units) in [
+        (date, &[('D', 86_400.)][..]),
+        (time, &[('H', 3_600.), ('M', 60.), ('S', 1.)][..]),
+    ] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+                continue;
+            }
+            let (_, multiplier) = units.iter().find(|(unit, _)| *unit == c).ok_or_else(bad)?;
+            seconds += number.parse::<f64>().map_err(|_| bad())? * multiplier;
+            number.clear();
+        }
+        if !number.is_empty() {
+            return Err(bad());
+        }
+    }
+
+    Ok(TimeDelta::milliseconds((seconds * 1000.).round() as i64))
+}
+
+/// Parse a frame rate attribute (`50` or `30000/1001`)
+fn parse_frame_rate(frame_rate: &str) -> Option<f64> {
+    match frame_rate.split_once('/') {
+        Some((numerator, denominator)) => {
+            Some(numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?)
+        }
+        None => frame_rate.parse().ok(),
+    }
+}
+
+/// Raw MPD document structure
+mod xml {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Mpd {
+        #[serde(rename = "@availabilityStartTime")]
+        pub availability_start_time: Option<String>,
+        #[serde(rename = "@timeShiftBufferDepth")]
+        pub time_shift_buffer_depth: Option<String>,
+        #[serde(rename = "Period", default)]
+        pub periods: Vec<Period>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Period {
+        #[serde(rename = "@start")]
+        pub start: Option<String>,
+        #[serde(rename = "AdaptationSet", default)]
+        pub adaptation_sets: Vec<AdaptationSet>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct AdaptationSet {
+        #[serde(rename = "@contentType")]
+        pub content_type: Option<String>,
+        #[serde(rename = "@mimeType")]
+        pub mime_type: Option<String>,
+        #[serde(rename = "@lang")]
+        pub lang: Option<String>,
+        #[serde(rename = "@codecs")]
+        pub codecs: Option<String>,
+        #[serde(rename = "@frameRate")]
+        pub frame_rate: Option<String>,
+        #[serde(rename = "Role", default)]
+        pub roles: Vec<Descriptor>,
+        #[serde(rename = "SegmentTemplate")]
+        pub segment_template: Option<SegmentTemplate>,
+        #[serde(rename = "Representation", default)]
+        pub representations: Vec<Representation>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Descriptor {
+        #[serde(rename = "@value")]
+        pub value: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Representation {
+        #[serde(rename = "@id")]
+        pub id: String,
+        #[serde(rename = "@bandwidth")]
+        pub bandwidth: u64,
+        #[serde(rename = "@width")]
+        pub width: Option<u32>,
+        #[serde(rename = "@height")]
+        pub height: Option<u32>,
+        #[serde(rename = "@frameRate")]
+        pub frame_rate: Option<String>,
+        #[serde(rename = "@mimeType")]
+        pub mime_type: Option<String>,
+        #[serde(rename = "@codecs")]
+        pub codecs: Option<String>,
+        #[serde(rename = "SegmentTemplate")]
+        pub segment_template: Option<SegmentTemplate>,
+    }
+
+    #[derive(Deserialize, Clone, Default)]
+    pub struct SegmentTemplate {
+        #[serde(rename = "@timescale")]
+        pub timescale: Option<u64>,
+        #[serde(rename = "@duration")]
+        pub duration: Option<u64>,
+        #[serde(rename = "@startNumber")]
+        pub start_number: Option<usize>,
+        #[serde(rename = "@initialization")]
+        pub initialization: Option<String>,
+        #[serde(rename = "@media")]
+        pub media: Option<String>,
+    }
+    impl SegmentTemplate {
+        /// Layer a representation's template over its adaptation set's
+        pub fn merge(parent: Option<&Self>, child: Option<&Self>) -> Self {
+            let parent = parent.cloned().unwrap_or_default();
+            let Some(child) = child.cloned() else {
+                return parent;
+            };
+            Self {
+                timescale: child.timescale.or(parent.timescale),
+                duration: child.duration.or(parent.duration),
+                start_number: child.start_number.or(parent.start_number),
+                initialization: child.initialization.or(parent.initialization),
+                media: child.media.or(parent.media),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use warp::Filter as _;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/live.mpd");
+    /// `availabilityStartTime` plus the period start of the fixture
+    const FIRST_SEGMENT_START: usize = 1_767_225_600 + 10;
+
+    fn fixture() -> Manifest {
+        Manifest::parse(FIXTURE).unwrap()
+    }
+
+    fn representation<'a>(manifest: &'a Manifest, id: &str) -> &'a Representation {
+        manifest
+            .representations
+            .iter()
+            .find(|representation| representation.id == id)
+            .unwrap()
+    }
+
+    /// MPD with a single video representation using the given template attributes
+    fn single(template: &str) -> String {
+        format!(
+            r#"<MPD><Period><AdaptationSet contentType="video">
+                <Representation id="v" bandwidth="1"><SegmentTemplate {template}/></Representation>
+            </AdaptationSet></Period></MPD>"#
+        )
+    }
+
+    #[test]
+    fn parses_manifest_attributes() {
+        let manifest = fixture();
+        assert_eq!(
+            manifest.availability_start_time,
+            DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z").unwrap()
+        );
+        assert_eq!(manifest.time_shift_buffer_depth, Some(TimeDelta::hours(2)));
+        // The text track is neither video nor audio
+        let ids = manifest
+            .representations
+            .iter()
+            .map(|representation| representation.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["v1080", "v720", "a128"]);
+
+        let video = representation(&manifest, "v1080");
+        assert_eq!(video.content_type, ContentType::Video);
+        assert_eq!(video.role.as_deref(), Some("main"));
+        assert_eq!(video.codecs.as_deref(), Some("avc1.640028"));
+        assert_eq!(video.quality_label().as_deref(), Some("1080p50"));
+        assert_eq!(video.template.period_start, TimeDelta::seconds(10));
+        // A representation's frame rate overrides its adaptation set's
+        let video = representation(&manifest, "v720");
+        assert_eq!(video.quality_label().as_deref(), Some("720p25"));
+
+        let audio = representation(&manifest, "a128");
+        assert_eq!(audio.content_type, ContentType::Audio);
+        assert_eq!(audio.language.as_deref(), Some("en"));
+        assert_eq!(audio.quality_label(), None);
+    }
+
+    #[test]
+    fn merges_segment_templates() {
+        let manifest = fixture();
+        // Entirely from the adaptation set
+        let video = &representation(&manifest, "v1080").template;
+        assert_eq!((video.timescale, video.duration), (1000, 3840));
+        assert_eq!(video.start_number, 100);
+        assert_eq!(video.media, "$RepresentationID$/$Number%05d$.m4s");
+        // Duration and media from the representation, the rest from the adaptation set
+        let audio = &representation(&manifest, "a128").template;
+        assert_eq!((audio.timescale, audio.duration), (48000, 184320));
+        assert_eq!(audio.start_number, 200);
+        assert_eq!(audio.initialization, "$RepresentationID$/init.mp4");
+        assert_eq!(audio.media, "$RepresentationID$_$Bandwidth$/$Number$.m4s");
+    }
+
+    #[test]
+    fn defaults_missing_attributes() {
+        let manifest =
+            Manifest::parse(&single(r#"duration="4" initialization="i" media="m""#)).unwrap();
+        assert_eq!(manifest.availability_start_time, DateTime::UNIX_EPOCH);
+        assert_eq!(manifest.time_shift_buffer_depth, None);
+        let template = &manifest.representations[0].template;
+        assert_eq!(template.timescale, 1);
+        assert_eq!(template.start_number, 1);
+        assert_eq!(template.period_start, TimeDelta::zero());
+    }
+
+    #[test]
+    fn rejects_unusable_templates() {
+        for template in [
+            r#"duration="0" initialization="i" media="m""#,
+            r#"timescale="0" duration="4" initialization="i" media="m""#,
+            r#"initialization="i" media="m""#,
+            r#"duration="4" media="m""#,
+            r#"duration="4" initialization="i""#,
+        ] {
+            assert!(
+                Manifest::parse(&single(template)).is_err(),
+                "accepted {template}"
+            );
+        }
+        assert!(Manifest::parse("<MPD></MPD>").is_err());
+        assert!(Manifest::parse("not xml").is_err());
+    }
+
+    #[test]
+    fn segment_timing() {
+        let manifest = fixture();
+        let video = representation(&manifest, "v1080");
+        assert_eq!(video.segment_duration(), 3.84);
+        assert_eq!(video.segment_idx(FIRST_SEGMENT_START), 100);
+        assert_eq!(video.segment_idx(FIRST_SEGMENT_START + 3), 100);
+        assert_eq!(video.segment_idx(FIRST_SEGMENT_START + 4), 101);
+        assert_eq!(video.segment_idx(FIRST_SEGMENT_START + 38), 109);
+        // Times before the first segment clamp to it
+        assert_eq!(video.segment_idx(0), 100);
+
+        assert_eq!(video.segment_start(100), FIRST_SEGMENT_START as f64);
+        assert!((video.segment_start(109) - (FIRST_SEGMENT_START as f64 + 34.56)).abs() < 1e-3);
+        assert_eq!(video.segment_start(0), FIRST_SEGMENT_START as f64);
+
+        // Each representation counts from its own start number
+        let audio = representation(&manifest, "a128");
+        assert_eq!(audio.segment_idx(FIRST_SEGMENT_START + 38), 209);
+        assert_eq!(audio.segment_start(200), FIRST_SEGMENT_START as f64);
+    }
+
+    #[test]
+    fn segment_urls() {
+        let manifest = fixture();
+        let video = representation(&manifest, "v1080");
+        assert_eq!(
+            video.init_url("https://cdn/").unwrap(),
+            "https://cdn/v1080/init.mp4"
+        );
+        assert_eq!(
+            video.segment_url("https://cdn/", 109).unwrap(),
+            "https://cdn/v1080/00109.m4s"
+        );
+        // Wider numbers aren't truncated
+        assert_eq!(
+            video.segment_url("https://cdn/", 1234567).unwrap(),
+            "https://cdn/v1080/1234567.m4s"
+        );
+
+        let audio = representation(&manifest, "a128");
+        assert_eq!(
+            audio.segment_url("https://cdn/", 209).unwrap(),
+            "https://cdn/a128_128000/209.m4s"
+        );
+    }
+
+    #[test]
+    fn expands_template_identifiers() {
+        let mut video = representation(&fixture(), "v1080").clone();
+        for (template, expected) in [
+            ("$Bandwidth%08d$-$Number%03d$", "05000000-007"),
+            ("cost$$/$Number$", "cost$/7"),
+        ] {
+            video.template.media = template.to_string();
+            assert_eq!(video.segment_url("", 7).unwrap(), expected);
+        }
+        for template in ["$Number", "$Time$"] {
+            video.template.media = template.to_string();
+            assert!(video.segment_url("", 7).is_err(), "expanded {template}");
+        }
+        // Initialization segments don't have a number
+        video.template.initialization = "$Number$.mp4".to_string();
+        assert!(video.init_url("").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        for (duration, milliseconds) in [
+            ("PT3.84S", 3_840),
+            ("PT0S", 0),
+            ("PT1M30S", 90_000),
+            ("PT2H", 7_200_000),
+            ("P1DT6H", 108_000_000),
+            ("P1D", 86_400_000),
+        ] {
+            assert_eq!(
+                parse_duration(duration).unwrap(),
+                TimeDelta::milliseconds(milliseconds),
+                "{duration}"
+            );
+        }
+        for duration in ["", "3S", "PT3", "PT3X", "P1H", "PTS.S"] {
+            assert!(parse_duration(duration).is_err(), "parsed {duration}");
+        }
+    }
+
+    #[test]
+    fn parses_qualities() {
+        for (quality, expected) in [
+            ("", Quality::Best),
+            ("best", Quality::Best),
+            ("BEST", Quality::Best),
+            ("audio-only", Quality::AudioOnly),
+            (
+                " 720p ",
+                Quality::Video {
+                    height: 720,
+                    frame_rate: None,
+                },
+            ),
+            (
+                "1080p50",
+                Quality::Video {
+                    height: 1080,
+                    frame_rate: Some(50),
+                },
+            ),
+            ("v1080", Quality::Representation("v1080".to_string())),
+            ("pc_hd", Quality::Representation("pc_hd".to_string())),
+            (
+                "720p_main",
+                Quality::Representation("720p_main".to_string()),
+            ),
+            (
+                "1080pfast",
+                Quality::Representation("1080pfast".to_string()),
+            ),
+        ] {
+            assert_eq!(quality.parse::<Quality>().unwrap(), expected, "{quality}");
+        }
+        // Display round-trips
+        for quality in [
+            "best",
+            "audio-only",
+            "720p",
+            "1080p50",
+            "v1080",
+            "720p_main",
+        ] {
+            assert_eq!(quality.parse::<Quality>().unwrap().to_string(), quality);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_from_url_prefix() {
+        let route = warp::path!("live" / ..)
+            .and(warp::path(MANIFEST_FILENAME))
+            .map(|| FIXTURE);
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let manifest = Manifest::fetch(&format!("http://{address}/live/"))
+            .await
+            .unwrap();
+        assert_eq!(manifest.representations.len(), 3);
+        assert!(Manifest::fetch(&format!("http://{address}/missing/"))
+            .await
+            .is_err());
+    }
+}
@@ -1,5 +1,21 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        name -> Varchar,
+        #[max_length = 64]
+        token_hash -> Bpchar,
+        #[max_length = 16]
+        scope -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     recordings (id) {
         id -> Int4,
@@ -14,6 +30,14 @@ diesel::table! {
         stage -> Int4,
         #[max_length = 32]
         channel -> Varchar,
+        #[max_length = 8]
+        output_format -> Varchar,
+        #[max_length = 32]
+        encode_profile -> Varchar,
+        encode_options -> Text,
+        #[max_length = 64]
+        quality -> Varchar,
+        uploaded -> Bool,
     }
 }
 
@@ -26,10 +50,15 @@ diesel::table! {
         can_upload -> Bool,
         can_delete -> Bool,
         superuser -> Bool,
+        password_hash -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(recordings -> users (user_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
     recordings,
     users,
 );
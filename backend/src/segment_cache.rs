@@ -0,0 +1,305 @@
+//! Segments shared between clip jobs, evicting the least recently used past `[cache] max_size_mb`.
+//! Jobs get hard links (or copies) of cached segments, so eviction never pulls files from
+//! under a running job.
+
+use crate::{clip::TEMP_DIRECTORY, config::CONFIG};
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{
+    fs::{copy, create_dir_all, hard_link, metadata, remove_file},
+    sync::Mutex,
+};
+
+/// Directory of the cache under the temp directory
+pub const DIRECTORY_NAME: &str = "cache";
+
+lazy_static! {
+    static ref CACHE: Mutex<SegmentCache> = Mutex::new(SegmentCache::load());
+    /// Locks for the files being downloaded, so each is only downloaded once
+    static ref IN_FLIGHT: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+/// `[cache]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Size the cache is kept under, `0` to disable it
+    pub max_size_mb: u64,
+}
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { max_size_mb: 4096 }
+    }
+}
+impl CacheConfig {
+    fn max_size(&self) -> u64 {
+        self.max_size_mb * 1024 * 1024
+    }
+}
+
+struct Entry {
+    size: u64,
+    /// Value of [`SegmentCache::clock`] when the entry was last used
+    last_used: u64,
+}
+
+struct SegmentCache {
+    root: PathBuf,
+    /// Entries by path relative to `root`
+    entries: HashMap<PathBuf, Entry>,
+    size: u64,
+    clock: u64,
+}
+impl SegmentCache {
+    /// Index the segments left by a previous run, oldest first
+    fn load() -> Self {
+        let root = PathBuf::from(TEMP_DIRECTORY).join(DIRECTORY_NAME);
+        let mut files = vec![];
+        let mut directories = vec![root.clone()];
+        while let Some(directory) = directories.pop() {
+            let Ok(entries) = std::fs::read_dir(&directory) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    directories.push(path);
+                } else if path
+                    .extension()
+                    .is_some_and(|extension| extension == "part")
+                {
+                    let _ = std::fs::remove_file(&path);
+                } else {
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((path, metadata.len(), modified));
+                }
+            }
+        }
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut cache = Self {
+            root,
+            entries: HashMap::new(),
+            size: 0,
+            clock: 0,
+        };
+        for (path, size, _) in files {
+            if let Ok(key) = path.strip_prefix(&cache.root) {
+                cache.insert(key.to_path_buf(), size);
+            }
+        }
+        if !cache.entries.is_empty() {
+            info!(
+                "segment cache has {count} segments ({mb} MiB)",
+                count = cache.entries.len(),
+                mb = cache.size / 1024 / 1024
+            );
+        }
+        cache
+    }
+
+    fn insert(&mut self, key: PathBuf, size: u64) {
+        self.clock += 1;
+        let previous = self.entries.insert(
+            key,
+            Entry {
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.size += size;
+        self.size -= previous.map_or(0, |entry| entry.size);
+    }
+
+    /// Mark an entry as used, returning whether it's cached
+    fn touch(&mut self, key: &Path) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if self.root.join(key).exists() => {
+                entry.last_used = self.clock;
+                true
+            }
+            Some(_) => {
+                // Removed from disk behind our back
+                let entry = self.entries.remove(key).expect("entry was just found");
+                self.size -= entry.size;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remove least recently used entries until the cache fits in `max_size`
+    async fn evict(&mut self, max_size: u64) {
+        if self.size <= max_size {
+            return;
+        }
+        let mut entries = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        for (_, key) in entries {
+            if self.size <= max_size {
+                break;
+            }
+            let entry = self.entries.remove(&key).expect("entry was just listed");
+            self.size -= entry.size;
+            debug!("evicting {key} from the segment cache", key = key.display());
+            if let Err(e) = remove_file(self.root.join(&key)).await {
+                warn!("failed to evict {key}: {e}", key = key.display());
+            }
+        }
+    }
+}
+
+/// Link or copy a cached file to `target`
+pub async fn link(source: &Path, target: &Path) -> Result<()> {
+    if hard_link(source, target).await.is_err() {
+        copy(source, target).await.with_context(|| {
+            anyhow!(
+                "copying {source} to {target}",
+                source = source.display(),
+                target = target.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Put the segment cached under `key` at `target`, calling `fetch` with the path to
+/// download it to if it isn't cached yet.
+/// Returns whether the segment was fetched.
+pub async fn fetch_into<F, Fut>(key: PathBuf, target: &Path, fetch: F) -> Result<bool>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let max_size = CONFIG.cache.max_size();
+    if max_size == 0 {
+        fetch(target.to_path_buf()).await?;
+        return Ok(true);
+    }
+
+    exclusive(key.clone(), || async {
+        let path = {
+            let mut cache = CACHE.lock().await;
+            let path = cache.root.join(&key);
+            if cache.touch(&key) {
+                link(&path, target).await?;
+                return Ok(false);
+            }
+            path
+        };
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await?;
+        }
+        fetch(path.clone()).await?;
+        let size = metadata(&path).await?.len();
+
+        let mut cache = CACHE.lock().await;
+        cache.insert(key.clone(), size);
+        link(&path, target).await?;
+        cache.evict(max_size).await;
+        Ok(true)
+    })
+    .await
+}
+
+/// Run `f` while no other job works on the file under `key`, waiting for those that are
+pub async fn exclusive<T, F, Fut>(key: PathBuf, f: F) -> T
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let in_flight = IN_FLIGHT
+        .lock()
+        .await
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let guard = in_flight.lock().await;
+    let result = f().await;
+    drop(guard);
+
+    // Jobs still waiting hold clones, and a new lock would let the next job in alongside them
+    let mut in_flight_locks = IN_FLIGHT.lock().await;
+    if Arc::strong_count(&in_flight) == 2 {
+        in_flight_locks.remove(&key);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use anyhow::bail;
+    use tokio::{fs::write, time::sleep};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn segments_are_fetched_once_at_a_time() {
+        let directory = PathBuf::from(format!("test-{}", Uuid::new_v4()));
+        let key = directory.join("1.m4s");
+        let targets = std::env::temp_dir().join(&directory);
+        create_dir_all(&targets).await.unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetching = Arc::new(AtomicUsize::new(0));
+
+        // The first fetch fails, so the second job fetches while the third one arrives
+        let jobs = [(0, true), (10, false), (80, false)].map(|(delay, fail)| {
+            let (key, target) = (key.clone(), targets.join(format!("{delay}.m4s")));
+            let (fetches, fetching) = (fetches.clone(), fetching.clone());
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(delay)).await;
+                fetch_into(key, &target, |path| async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(fetching.fetch_add(1, Ordering::SeqCst), 0, "fetched twice");
+                    sleep(Duration::from_millis(60)).await;
+                    fetching.fetch_sub(1, Ordering::SeqCst);
+                    if fail {
+                        bail!("download failed");
+                    }
+                    write(path, b"segment").await?;
+                    Ok(())
+                })
+                .await
+            })
+        });
+        let mut results = vec![];
+        for job in jobs {
+            results.push(job.await.unwrap().map_err(|e| e.to_string()));
+        }
+
+        assert_eq!(
+            results,
+            [Err("download failed".to_string()), Ok(true), Ok(false)]
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(!IN_FLIGHT.lock().await.contains_key(&key));
+        let _ = tokio::fs::remove_dir_all(&targets).await;
+        let _ = tokio::fs::remove_dir_all(CACHE.lock().await.root.join(&directory)).await;
+    }
+}
@@ -0,0 +1,337 @@
+//! Channel catalogue loaded from `SOURCES_PATH` (defaults to `sources.toml`) and reloaded
+//! whenever the file changes
+
+use crate::{
+    health,
+    manifest::{ContentType, Manifest},
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::Deserialize;
+use tokio::time::sleep;
+
+/// CDN hosts serving the same BBC services, split around the push variant
+const CDN_HOSTS: [(&str, &str); 3] = [
+    ("vs-cmaf-", "-uk-live.akamaized.net"),
+    ("vs-cmaf-", "-uk.live.fastly.md.bbci.co.uk"),
+    ("vs-cmaf-", "-uk.live.cf.md.bbci.co.uk"),
+];
+/// How often the file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type Catalogue = HashMap<String, Arc<SourceEntry>>;
+
+lazy_static! {
+    static ref SOURCES_PATH: PathBuf =
+        PathBuf::from(std::env::var("SOURCES_PATH").unwrap_or_else(|_| "sources.toml".to_string()));
+    /// Empty until [`initialize`] loads the file
+    static ref SOURCES: RwLock<Arc<Catalogue>> = RwLock::new(Arc::default());
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourcesFile {
+    #[serde(rename = "source", default)]
+    sources: Vec<SourceEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SourceEntry {
+    pub name: String,
+    pub id: usize,
+    /// Heading in the channel picker
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Equivalent URL prefixes on different CDNs, preferred first
+    pub url_prefixes: Vec<String>,
+    /// Also try the same path on the other [`CDN_HOSTS`]
+    #[serde(default = "default_cdn_failover")]
+    pub cdn_failover: bool,
+    /// Representation IDs to offer (all when unset)
+    #[serde(default)]
+    pub representations: Option<Vec<String>>,
+    /// Preferred audio track language
+    #[serde(default)]
+    pub audio_language: Option<String>,
+}
+fn default_cdn_failover() -> bool {
+    true
+}
+impl SourceEntry {
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("source {} has no name", self.id);
+        }
+        if self.url_prefixes.is_empty() {
+            bail!("{} has no URL prefixes", self.name);
+        }
+        for url_prefix in &self.url_prefixes {
+            if !(url_prefix.starts_with("https://") || url_prefix.starts_with("http://"))
+                || !url_prefix.ends_with('/')
+            {
+                bail!(
+                    "{}: URL prefix {url_prefix} must be an http(s) URL ending in /",
+                    self.name
+                );
+            }
+        }
+        if self
+            .representations
+            .as_ref()
+            .is_some_and(|representations| representations.is_empty())
+        {
+            bail!("{} allows no representations", self.name);
+        }
+        Ok(())
+    }
+
+    /// Append the same path on the other CDNs for prefixes hosted on one of [`CDN_HOSTS`]
+    fn add_cdn_alternatives(&mut self) {
+        let alternatives = self
+            .url_prefixes
+            .iter()
+            .filter_map(|url_prefix| {
+                let (host, path) = url_prefix.strip_prefix("https://")?.split_once('/')?;
+                let variant = CDN_HOSTS
+                    .iter()
+                    .find_map(|(start, end)| host.strip_prefix(start)?.strip_suffix(end))?;
+                Some(
+                    CDN_HOSTS
+                        .iter()
+                        .map(move |(start, end)| format!("https://{start}{variant}{end}/{path}")),
+                )
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        for alternative in alternatives {
+            if !self.url_prefixes.contains(&alternative) {
+                self.url_prefixes.push(alternative);
+            }
+        }
+    }
+
+    /// Fetch the source's manifest, limited to the representations it offers
+    pub async fn manifest(&self) -> Result<Manifest> {
+        let mut manifest = Manifest::fetch_any(&self.url_prefixes).await?;
+        if let Some(representations) = &self.representations {
+            manifest
+                .representations
+                .retain(|representation| representations.contains(&representation.id));
+        }
+        if let Some(language) = &self.audio_language {
+            let has_language = manifest.representations(ContentType::Audio).any(|audio| {
+                audio
+                    .language
+                    .as_ref()
+                    .is_some_and(|lang| lang.eq_ignore_ascii_case(language))
+            });
+            if has_language {
+                manifest.representations.retain(|representation| {
+                    representation.content_type != ContentType::Audio
+                        || representation
+                            .language
+                            .as_ref()
+                            .is_some_and(|lang| lang.eq_ignore_ascii_case(language))
+                });
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Origins of the source in the order new segments try them
+    pub fn ranked_url_prefixes(&self) -> Vec<&String> {
+        health::rank(&self.url_prefixes)
+    }
+}
+
+/// Read and validate a sources file
+fn load(path: &Path) -> Result<Catalogue> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| anyhow!("reading {path}", path = path.display()))?;
+    let catalogue =
+        parse(&contents).with_context(|| anyhow!("loading {path}", path = path.display()))?;
+    info!(
+        "loaded {count} sources from {path}",
+        count = catalogue.len(),
+        path = path.display()
+    );
+    Ok(catalogue)
+}
+
+/// Parse and validate the contents of a sources file
+fn parse(contents: &str) -> Result<Catalogue> {
+    let SourcesFile { sources } = toml::from_str(contents)?;
+    if sources.is_empty() {
+        bail!("there are no sources");
+    }
+
+    let mut ids = HashSet::new();
+    let mut catalogue = Catalogue::new();
+    for mut source in sources {
+        source.validate()?;
+        if !ids.insert(source.id) {
+            bail!("source id {} is used more than once", source.id);
+        }
+        if source.cdn_failover {
+            source.add_cdn_alternatives();
+        }
+        let name = source.name.clone();
+        if catalogue.insert(name.clone(), Arc::new(source)).is_some() {
+            bail!("source {name} is listed more than once");
+        }
+    }
+    Ok(catalogue)
+}
+
+/// Load the sources, failing if the file is missing or invalid
+pub fn initialize() -> Result<()> {
+    *SOURCES.write().expect("sources lock poisoned") = Arc::new(load(&SOURCES_PATH)?);
+    Ok(())
+}
+
+/// Look up a source by name
+pub fn get(name: &str) -> Option<Arc<SourceEntry>> {
+    all().get(name).cloned()
+}
+
+/// The current catalogue
+pub fn all() -> Arc<Catalogue> {
+    SOURCES.read().expect("sources lock poisoned").clone()
+}
+
+/// Reload the sources whenever the file changes, keeping the previous catalogue if the new
+/// one is invalid
+pub async fn watch() {
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let mut last_modified = modified(&SOURCES_PATH);
+    loop {
+        sleep(RELOAD_INTERVAL).await;
+        let current = modified(&SOURCES_PATH);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        match load(&SOURCES_PATH) {
+            Ok(catalogue) => {
+                *SOURCES.write().expect("sources lock poisoned") = Arc::new(catalogue);
+            }
+            Err(e) => error!("not reloading sources: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCES: &str = r#"
+        [[source]]
+        name = "bbc_one_hd"
+        id = 1
+        group = "BBC One"
+        url_prefixes = ["https://vs-cmaf-pushb-uk-live.akamaized.net/x=4/i=urn:bbc:pips:service:bbc_one_hd/"]
+
+        [[source]]
+        name = "bbc_two_hd"
+        id = 2
+        url_prefixes = ["https://vs-cmaf-pushb-uk-live.akamaized.net/x=4/i=urn:bbc:pips:service:bbc_two_hd/"]
+        cdn_failover = false
+
+        [[source]]
+        name = "elsewhere"
+        id = 3
+        url_prefixes = ["https://cdn.example/live/"]
+        representations = ["video=5070000"]
+    "#;
+
+    #[test]
+    fn parses_catalogue() {
+        let catalogue = parse(SOURCES).unwrap();
+        assert_eq!(catalogue.len(), 3);
+        let one = &catalogue["bbc_one_hd"];
+        assert_eq!(one.group.as_deref(), Some("BBC One"));
+        assert_eq!(
+            one.url_prefixes,
+            [
+                "https://vs-cmaf-pushb-uk-live.akamaized.net/x=4/i=urn:bbc:pips:service:bbc_one_hd/",
+                "https://vs-cmaf-pushb-uk.live.fastly.md.bbci.co.uk/x=4/i=urn:bbc:pips:service:bbc_one_hd/",
+                "https://vs-cmaf-pushb-uk.live.cf.md.bbci.co.uk/x=4/i=urn:bbc:pips:service:bbc_one_hd/",
+            ]
+        );
+        // Only prefixes on the known CDNs get alternatives, and only when enabled
+        assert_eq!(catalogue["bbc_two_hd"].url_prefixes.len(), 1);
+        let elsewhere = &catalogue["elsewhere"];
+        assert_eq!(elsewhere.url_prefixes, ["https://cdn.example/live/"]);
+        assert_eq!(
+            elsewhere.representations.as_deref(),
+            Some(&["video=5070000".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn cdn_alternatives_are_not_repeated() {
+        let catalogue = parse(
+            r#"
+            [[source]]
+            name = "bbc_one_hd"
+            id = 1
+            url_prefixes = [
+                "https://vs-cmaf-pushb-uk.live.cf.md.bbci.co.uk/a/",
+                "https://vs-cmaf-pushb-uk-live.akamaized.net/a/",
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            catalogue["bbc_one_hd"].url_prefixes,
+            [
+                "https://vs-cmaf-pushb-uk.live.cf.md.bbci.co.uk/a/",
+                "https://vs-cmaf-pushb-uk-live.akamaized.net/a/",
+                "https://vs-cmaf-pushb-uk.live.fastly.md.bbci.co.uk/a/",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_catalogues() {
+        let source = |name: &str, id: usize, url_prefixes: &str| {
+            format!("[[source]]\nname = \"{name}\"\nid = {id}\nurl_prefixes = {url_prefixes}\n")
+        };
+        let prefix = r#"["https://cdn.example/live/"]"#;
+        for (contents, message) in [
+            (String::new(), "there are no sources"),
+            (
+                source("a", 1, prefix) + &source("a", 2, prefix),
+                "source a is listed more than once",
+            ),
+            (
+                source("a", 1, prefix) + &source("b", 1, prefix),
+                "source id 1 is used more than once",
+            ),
+            (source("a", 1, "[]"), "a has no URL prefixes"),
+            (
+                source("a", 1, r#"["https://cdn.example/live"]"#),
+                "a: URL prefix https://cdn.example/live must be an http(s) URL ending in /",
+            ),
+            (source(" ", 1, prefix), "source 1 has no name"),
+            (
+                source("a", 1, prefix) + "representations = []\n",
+                "a allows no representations",
+            ),
+        ] {
+            let error = parse(&contents).expect_err(message).to_string();
+            assert_eq!(error, message);
+        }
+        assert!(parse(&(source("a", 1, prefix) + "colour = \"red\"\n")).is_err());
+    }
+}
@@ -0,0 +1,927 @@
+//! Where finished clips are stored
+
+use crate::consts::{WEBDAV_PASSWORD, WEBDAV_URL, WEBDAV_USERNAME};
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use bytes::Bytes;
+use chrono::TimeDelta;
+use futures_util::{Stream, TryStreamExt as _};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{
+    fs::{create_dir_all, remove_file, rename, File},
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+    sync::mpsc::UnboundedSender,
+    time::sleep,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+pub type Storage = Arc<dyn StorageBackend>;
+/// Contents of a stored object
+pub type ObjectStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// Key a clip's result is stored under
+pub fn clip_key(uuid: &str, extension: &str) -> String {
+    format!("{uuid}.{extension}")
+}
+
+/// Error for an object that isn't in storage, from [`StorageBackend::read`]
+#[derive(Debug)]
+pub struct ObjectNotFound(pub String);
+impl std::fmt::Display for ObjectNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is missing from storage", self.0)
+    }
+}
+impl std::error::Error for ObjectNotFound {}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Begin storing `size` bytes under a key, replacing anything already there
+    async fn start_upload(
+        &self,
+        key: &str,
+        size: u64,
+        chunk_size: u64,
+    ) -> Result<Box<dyn UploadSession>>;
+    /// Size of the object stored under a key, if there is one
+    async fn size(&self, key: &str) -> Result<Option<u64>>;
+    /// Read the inclusive byte range `[start, end]` of the object stored under a key,
+    /// failing with [`ObjectNotFound`] if there's none
+    async fn read(&self, key: &str, range: (u64, u64)) -> Result<ObjectStream>;
+    /// Remove the object stored under a key; a missing object isn't an error
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// An upload in progress, sent in chunks
+#[async_trait]
+pub trait UploadSession: Send {
+    /// Smallest chunk the backend accepts (other than the last)
+    fn min_chunk_size(&self) -> u64 {
+        1
+    }
+    /// Whether the file is sent in one streamed request with `write_file` rather than in chunks
+    fn takes_whole_file(&self) -> bool {
+        false
+    }
+    /// Store the whole file, sending the number of bytes sent so far through `progress`
+    async fn write_file(&mut self, _file: File, _progress: UnboundedSender<u64>) -> Result<()> {
+        bail!("this backend only takes chunks")
+    }
+    /// Bytes the backend has confirmed, from which an interrupted upload resumes
+    async fn confirmed_offset(&mut self) -> Result<u64>;
+    /// Store a chunk starting at `offset`
+    async fn write_chunk(&mut self, offset: u64, chunk: Vec<u8>) -> Result<()>;
+    /// Make the uploaded bytes available under the key
+    async fn finish(&mut self) -> Result<()>;
+    /// Discard an upload that won't be finished
+    async fn abort(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// `[upload]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Bytes sent per request
+    pub chunk_size: u64,
+    /// Attempts per chunk after the first before the upload fails
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every retry after
+    pub retry_delay_seconds: u64,
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 8 * 1024 * 1024, /* MiB */
+            retries: 5,
+            retry_delay_seconds: 2,
+        }
+    }
+}
+
+/// Upload a file in chunks, resuming from the last confirmed offset after a failure, or in
+/// one request for backends taking whole files.
+/// The number of bytes confirmed so far is sent through `progress` after every chunk.
+pub async fn upload_file(
+    storage: &Storage,
+    path: &Path,
+    key: &str,
+    config: &UploadConfig,
+    progress: UnboundedSender<u64>,
+) -> Result<()> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| anyhow!("opening {}", path.display()))?;
+    let size = file.metadata().await?.len();
+    let mut session = storage.start_upload(key, size, config.chunk_size).await?;
+    let chunk_size = config.chunk_size.max(session.min_chunk_size());
+
+    let result = async {
+        if session.takes_whole_file() {
+            let mut failures = 0;
+            loop {
+                file.seek(SeekFrom::Start(0)).await?;
+                let attempt = session
+                    .write_file(file.try_clone().await?, progress.clone())
+                    .await;
+                match attempt {
+                    Ok(()) => return session.finish().await,
+                    Err(e) if failures < config.retries => {
+                        let delay = config.retry_delay_seconds << failures;
+                        failures += 1;
+                        warn!("{key}: upload failed, retry {failures} in {delay}s: {e:#}");
+                        sleep(Duration::from_secs(delay)).await;
+                    }
+                    Err(e) => return Err(e.context(anyhow!("upload of {key} failed"))),
+                }
+            }
+        }
+
+        let mut offset = 0;
+        let mut failures = 0;
+        while offset < size {
+            let attempt = async {
+                let length = chunk_size.min(size - offset);
+                let mut chunk = vec![0; length as usize];
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.read_exact(&mut chunk).await?;
+                session.write_chunk(offset, chunk).await?;
+                Ok::<_, anyhow::Error>(offset + length)
+            }
+            .await;
+            match attempt {
+                Ok(next_offset) => {
+                    offset = next_offset;
+                    failures = 0;
+                    let _ = progress.send(offset);
+                }
+                Err(e) if failures < config.retries => {
+                    let delay = config.retry_delay_seconds << failures;
+                    failures += 1;
+                    warn!("{key}: chunk at {offset} failed, retry {failures} in {delay}s: {e:#}");
+                    sleep(Duration::from_secs(delay)).await;
+                    match session.confirmed_offset().await {
+                        Ok(confirmed) => offset = confirmed.min(size),
+                        Err(e) => warn!("{key}: failed to find the confirmed offset: {e:#}"),
+                    }
+                    debug!("{key}: resuming from {offset}");
+                }
+                Err(e) => return Err(e.context(anyhow!("chunk at {offset} of {key} failed"))),
+            }
+        }
+        session.finish().await
+    }
+    .await;
+
+    if result.is_err() {
+        if let Err(e) = session.abort().await {
+            warn!("{key}: failed to abort upload: {e:#}");
+        }
+    }
+    result
+}
+
+/// `[storage]` section of the configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// WebDAV server at `WEBDAV_URL` with `WEBDAV_USERNAME`/`WEBDAV_PASSWORD`
+    Webdav {
+        /// Collection under `WEBDAV_URL` to store clips in
+        #[serde(default = "default_webdav_collection")]
+        collection: String,
+        /// Send files larger than `[upload] chunk_size` in partial `PUT`s, which the server
+        /// has to support, instead of one streamed `PUT`. Only chunked uploads resume from the
+        /// last confirmed chunk; a failed streamed `PUT` starts again from the beginning.
+        #[serde(default)]
+        chunked: bool,
+    },
+    /// A directory on the backend's filesystem
+    Local { directory: PathBuf },
+    /// S3-compatible object storage, with credentials from the standard AWS environment
+    S3 {
+        bucket: String,
+        region: Option<String>,
+        /// Endpoint for non-AWS providers (e.g. MinIO, R2)
+        endpoint: Option<String>,
+        /// Prefix for object keys
+        #[serde(default)]
+        prefix: String,
+        /// Address buckets by path rather than subdomain
+        #[serde(default)]
+        path_style: bool,
+    },
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Webdav {
+            collection: default_webdav_collection(),
+            chunked: false,
+        }
+    }
+}
+fn default_webdav_collection() -> String {
+    "bbcd".to_string()
+}
+
+/// Create the storage backend from the configuration
+pub async fn connect(config: &StorageConfig) -> Result<Storage> {
+    info!("using {config:?} storage");
+    Ok(match config {
+        StorageConfig::Webdav {
+            collection,
+            chunked,
+        } => Arc::new(WebDav {
+            client: reqwest::ClientBuilder::new()
+                .connect_timeout(TimeDelta::seconds(10).to_std()?)
+                .build()?,
+            url: format!("{url}/{collection}", url = WEBDAV_URL.trim_end_matches('/')),
+            chunked: *chunked,
+        }),
+        StorageConfig::Local { directory } => {
+            create_dir_all(directory)
+                .await
+                .with_context(|| anyhow!("creating {}", directory.display()))?;
+            Arc::new(LocalDirectory::new(directory.clone()))
+        }
+        StorageConfig::S3 {
+            bucket,
+            region,
+            endpoint,
+            prefix,
+            path_style,
+        } => {
+            let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+            if let Some(region) = region {
+                loader = loader.region(aws_config::Region::new(region.clone()));
+            }
+            if let Some(endpoint) = endpoint {
+                loader = loader.endpoint_url(endpoint);
+            }
+            let s3_config = aws_sdk_s3::config::Builder::from(&loader.load().await)
+                .force_path_style(*path_style)
+                .build();
+            Arc::new(S3 {
+                client: aws_sdk_s3::Client::from_conf(s3_config),
+                bucket: bucket.clone(),
+                prefix: prefix.clone(),
+            })
+        }
+    })
+}
+
+pub struct WebDav {
+    client: reqwest::Client,
+    /// URL of the collection clips are stored in
+    url: String,
+    /// Upload in partial `PUT`s rather than one streamed `PUT`
+    chunked: bool,
+}
+#[async_trait]
+impl StorageBackend for WebDav {
+    async fn start_upload(
+        &self,
+        key: &str,
+        size: u64,
+        chunk_size: u64,
+    ) -> Result<Box<dyn UploadSession>> {
+        let url = format!("{}/{key}", self.url);
+        debug!("uploading {size} bytes to {url}");
+        Ok(Box::new(WebDavUpload {
+            client: self.client.clone(),
+            // Files that need several requests are assembled next to the destination
+            part_url: if self.chunked && size > chunk_size {
+                Some(format!("{url}.part"))
+            } else {
+                None
+            },
+            chunked: self.chunked,
+            url,
+            size,
+        }))
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        let url = format!("{}/{key}", self.url);
+        let resp = self
+            .client
+            .head(&url)
+            .basic_auth(&*WEBDAV_USERNAME, Some(&*WEBDAV_PASSWORD))
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {url}"))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(resp
+            .error_for_status()?
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok()))
+    }
+
+    async fn read(&self, key: &str, (start, end): (u64, u64)) -> Result<ObjectStream> {
+        let url = format!("{}/{key}", self.url);
+        let resp = self
+            .client
+            .get(&url)
+            .basic_auth(&*WEBDAV_USERNAME, Some(&*WEBDAV_PASSWORD))
+            .header(reqwest::header::RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {url}"))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            Err(ObjectNotFound(key.to_string()))?;
+        }
+        let resp = resp
+            .error_for_status()
+            .with_context(|| anyhow!("request to {url}"))?;
+        if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT && start > 0 {
+            bail!("{url} doesn't support range requests");
+        }
+        // A server ignoring the range sends everything from the start, so cut it to length
+        let reader = StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other));
+        Ok(Box::pin(ReaderStream::new(reader.take(end - start + 1))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let url = format!("{}/{key}", self.url);
+        let resp = self
+            .client
+            .delete(&url)
+            .basic_auth(&*WEBDAV_USERNAME, Some(&*WEBDAV_PASSWORD))
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {url}"))?;
+        if resp.status() != reqwest::StatusCode::NOT_FOUND {
+            resp.error_for_status()
+                .with_context(|| anyhow!("deleting {url}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// An upload to a WebDAV server, as one streamed `PUT` unless chunked. Chunks after the first
+/// are sent as partial `PUT`s with a `Content-Range`, which the server has to support for
+/// files larger than a chunk.
+pub struct WebDavUpload {
+    client: reqwest::Client,
+    url: String,
+    part_url: Option<String>,
+    chunked: bool,
+    size: u64,
+}
+impl WebDavUpload {
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, url)
+            .basic_auth(&*WEBDAV_USERNAME, Some(&*WEBDAV_PASSWORD))
+    }
+}
+#[async_trait]
+impl UploadSession for WebDavUpload {
+    fn takes_whole_file(&self) -> bool {
+        !self.chunked
+    }
+
+    async fn write_file(&mut self, file: File, progress: UnboundedSender<u64>) -> Result<()> {
+        let mut sent = 0;
+        let body = ReaderStream::new(file).inspect_ok(move |bytes| {
+            sent += bytes.len() as u64;
+            let _ = progress.send(sent);
+        });
+        let resp = self
+            .request(reqwest::Method::PUT, &self.url)
+            .header(reqwest::header::CONTENT_LENGTH, self.size)
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {url}", url = self.url))?;
+        if !resp.status().is_success() {
+            bail!(
+                "upload to {url} failed with {status}: {body}",
+                url = self.url,
+                status = resp.status(),
+                body = resp.text().await.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    async fn confirmed_offset(&mut self) -> Result<u64> {
+        let Some(part_url) = &self.part_url else {
+            return Ok(0);
+        };
+        let resp = self
+            .request(reqwest::Method::HEAD, part_url)
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {part_url}"))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(0);
+        }
+        let resp = resp.error_for_status()?;
+        Ok(resp
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok())
+            .unwrap_or(0))
+    }
+
+    async fn write_chunk(&mut self, offset: u64, chunk: Vec<u8>) -> Result<()> {
+        let url = self.part_url.as_ref().unwrap_or(&self.url);
+        let mut request = self.request(reqwest::Method::PUT, url);
+        if offset > 0 {
+            request = request.header(
+                reqwest::header::CONTENT_RANGE,
+                format!(
+                    "bytes {offset}-{end}/{size}",
+                    end = offset + chunk.len() as u64 - 1,
+                    size = self.size
+                ),
+            );
+        }
+        let resp = request
+            .body(chunk)
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {url}"))?;
+        if !resp.status().is_success() {
+            bail!(
+                "upload to {url} failed with {status}: {body}",
+                status = resp.status(),
+                body = resp.text().await.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        let Some(part_url) = self.part_url.clone() else {
+            return Ok(());
+        };
+        // Servers ignoring `Content-Range` overwrite the part with every chunk
+        let confirmed = self.confirmed_offset().await?;
+        if confirmed != self.size {
+            bail!(
+                "{part_url} has {confirmed} of {size} bytes, the server may not support \
+                 partial PUTs",
+                size = self.size
+            );
+        }
+        let resp = self
+            .request(reqwest::Method::from_bytes(b"MOVE")?, &part_url)
+            .header("Destination", &self.url)
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .with_context(|| anyhow!("request to {part_url}"))?;
+        if !resp.status().is_success() {
+            bail!(
+                "moving {part_url} to {url} failed with {status}",
+                url = self.url,
+                status = resp.status()
+            );
+        }
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        let Some(part_url) = &self.part_url else {
+            return Ok(());
+        };
+        self.request(reqwest::Method::DELETE, part_url)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct LocalDirectory {
+    directory: PathBuf,
+}
+impl LocalDirectory {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+#[async_trait]
+impl StorageBackend for LocalDirectory {
+    async fn start_upload(
+        &self,
+        key: &str,
+        _size: u64,
+        _chunk_size: u64,
+    ) -> Result<Box<dyn UploadSession>> {
+        let destination = self.directory.join(key);
+        let part_path = self.directory.join(format!("{key}.part"));
+        debug!("copying to {}", destination.display());
+        let file = File::create(&part_path)
+            .await
+            .with_context(|| anyhow!("creating {}", part_path.display()))?;
+        Ok(Box::new(LocalUpload {
+            file,
+            part_path,
+            destination,
+        }))
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        match tokio::fs::metadata(self.directory.join(key)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read(&self, key: &str, (start, end): (u64, u64)) -> Result<ObjectStream> {
+        let path = self.directory.join(key);
+        let mut file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ObjectNotFound(key.to_string()))?
+            }
+            Err(e) => Err(anyhow!(e).context(anyhow!("opening {}", path.display())))?,
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(ReaderStream::new(file.take(end - start + 1))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.directory.join(key);
+        match remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(anyhow!(e).context(anyhow!("removing {}", path.display())))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A copy into a partial file that's renamed into place once complete
+pub struct LocalUpload {
+    file: File,
+    part_path: PathBuf,
+    destination: PathBuf,
+}
+#[async_trait]
+impl UploadSession for LocalUpload {
+    async fn confirmed_offset(&mut self) -> Result<u64> {
+        Ok(self.file.metadata().await?.len())
+    }
+
+    async fn write_chunk(&mut self, offset: u64, chunk: Vec<u8>) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file
+            .write_all(&chunk)
+            .await
+            .with_context(|| anyhow!("writing to {}", self.part_path.display()))?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        rename(&self.part_path, &self.destination)
+            .await
+            .with_context(|| {
+                anyhow!(
+                    "moving {} to {}",
+                    self.part_path.display(),
+                    self.destination.display()
+                )
+            })?;
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        remove_file(&self.part_path).await?;
+        Ok(())
+    }
+}
+
+/// Smallest part of a multipart upload other than the last
+const S3_MIN_PART_SIZE: u64 = 5 * 1024 * 1024; /* MiB */
+
+pub struct S3 {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+}
+#[async_trait]
+impl StorageBackend for S3 {
+    async fn start_upload(
+        &self,
+        key: &str,
+        size: u64,
+        _chunk_size: u64,
+    ) -> Result<Box<dyn UploadSession>> {
+        let key = format!("{}{key}", self.prefix);
+        debug!("uploading to s3://{}/{key}", self.bucket);
+        // A multipart upload needs at least one part, and every part but the last a full one
+        if size < S3_MIN_PART_SIZE {
+            return Ok(Box::new(S3Put {
+                client: self.client.clone(),
+                bucket: self.bucket.clone(),
+                key,
+            }));
+        }
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .with_context(|| anyhow!("starting upload to s3://{}/{key}", self.bucket))?;
+        Ok(Box::new(S3Upload {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            upload_id: upload
+                .upload_id
+                .context("no upload ID for multipart upload")?,
+            key,
+            parts: vec![],
+        }))
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        let key = format!("{}{key}", self.prefix);
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+        {
+            Ok(head) => Ok(head.content_length.map(|length| length as u64)),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(anyhow!(e).context(anyhow!("head of s3://{}/{key}", self.bucket))),
+        }
+    }
+
+    async fn read(&self, key: &str, (start, end): (u64, u64)) -> Result<ObjectStream> {
+        let key = format!("{}{key}", self.prefix);
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .range(format!("bytes={start}-{end}"))
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                Err(ObjectNotFound(key))?
+            }
+            Err(e) => Err(anyhow!(e).context(anyhow!("reading s3://{}/{key}", self.bucket)))?,
+        };
+        Ok(Box::pin(ReaderStream::new(object.body.into_async_read())))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let key = format!("{}{key}", self.prefix);
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .with_context(|| anyhow!("deleting s3://{}/{key}", self.bucket))?;
+        Ok(())
+    }
+}
+
+/// A multipart upload, with one part per chunk
+pub struct S3Upload {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    /// Parts the bucket has acknowledged, with their lengths
+    parts: Vec<(CompletedPart, u64)>,
+}
+#[async_trait]
+impl UploadSession for S3Upload {
+    fn min_chunk_size(&self) -> u64 {
+        S3_MIN_PART_SIZE
+    }
+
+    async fn confirmed_offset(&mut self) -> Result<u64> {
+        Ok(self.parts.iter().map(|(_, length)| length).sum())
+    }
+
+    async fn write_chunk(&mut self, _offset: u64, chunk: Vec<u8>) -> Result<()> {
+        // Chunks are only sent after the confirmed offset, so parts are in order
+        let part_number = self.parts.len() as i32 + 1;
+        let length = chunk.len() as u64;
+        let part = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(chunk))
+            .send()
+            .await
+            .with_context(|| anyhow!("uploading part {part_number} of {}", self.key))?;
+        self.parts.push((
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag)
+                .build(),
+            length,
+        ));
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(
+                        self.parts.iter().map(|(part, _)| part.clone()).collect(),
+                    ))
+                    .build(),
+            )
+            .send()
+            .await
+            .with_context(|| anyhow!("completing upload to s3://{}/{}", self.bucket, self.key))?;
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// A file smaller than a part, stored with a single `PutObject`
+pub struct S3Put {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+}
+#[async_trait]
+impl UploadSession for S3Put {
+    fn takes_whole_file(&self) -> bool {
+        true
+    }
+
+    async fn write_file(&mut self, mut file: File, progress: UnboundedSender<u64>) -> Result<()> {
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).await?;
+        let size = contents.len() as u64;
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .body(ByteStream::from(contents))
+            .send()
+            .await
+            .with_context(|| anyhow!("uploading to s3://{}/{}", self.bucket, self.key))?;
+        let _ = progress.send(size);
+        Ok(())
+    }
+
+    async fn confirmed_offset(&mut self) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn write_chunk(&mut self, _offset: u64, _chunk: Vec<u8>) -> Result<()> {
+        bail!("files smaller than a part are stored whole")
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{collections::HashMap, sync::Mutex};
+
+    use tokio::sync::mpsc::unbounded_channel;
+    use warp::{http::StatusCode, Filter as _};
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// WebDAV server storing files in memory that ignores `Content-Range`, like servers
+    /// without partial `PUT` support do
+    fn serve_webdav() -> (String, Files) {
+        let files = Files::default();
+        let route = warp::method()
+            .and(warp::path::param::<String>())
+            .and(warp::header::optional::<String>("destination"))
+            .and(warp::body::bytes())
+            .map({
+                let files = files.clone();
+                move |method: warp::http::Method,
+                      name: String,
+                      destination: Option<String>,
+                      body: Bytes| {
+                    let mut files = files.lock().unwrap();
+                    let response = warp::http::Response::builder();
+                    match method.as_str() {
+                        "PUT" => {
+                            files.insert(name, body.to_vec());
+                            response.status(StatusCode::CREATED).body(vec![])
+                        }
+                        "HEAD" => match files.get(&name) {
+                            Some(file) => {
+                                response.header("content-length", file.len()).body(vec![])
+                            }
+                            None => response.status(StatusCode::NOT_FOUND).body(vec![]),
+                        },
+                        "MOVE" => {
+                            let file = files.remove(&name).unwrap();
+                            let destination = destination.unwrap();
+                            files.insert(destination.rsplit('/').next().unwrap().to_string(), file);
+                            response.status(StatusCode::CREATED).body(vec![])
+                        }
+                        "DELETE" => {
+                            files.remove(&name);
+                            response.status(StatusCode::NO_CONTENT).body(vec![])
+                        }
+                        _ => response.status(StatusCode::METHOD_NOT_ALLOWED).body(vec![]),
+                    }
+                }
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{address}"), files)
+    }
+
+    async fn upload(chunked: bool) -> (Result<()>, Files, Option<u64>) {
+        std::env::set_var("WEBDAV_USERNAME", "user");
+        std::env::set_var("WEBDAV_PASSWORD", "password");
+        let (url, files) = serve_webdav();
+        let storage: Storage = Arc::new(WebDav {
+            client: reqwest::Client::new(),
+            url,
+            chunked,
+        });
+        let path = std::env::temp_dir().join(format!("bbcd-test-upload-{chunked}"));
+        tokio::fs::write(&path, b"0123456789").await.unwrap();
+        let config = UploadConfig {
+            chunk_size: 4,
+            retries: 0,
+            retry_delay_seconds: 0,
+        };
+        let (tx, mut rx) = unbounded_channel();
+        let result = upload_file(&storage, &path, "clip.mp4", &config, tx).await;
+        let mut progress = None;
+        while let Ok(sent) = rx.try_recv() {
+            progress = Some(sent);
+        }
+        (result, files, progress)
+    }
+
+    #[tokio::test]
+    async fn webdav_uploads_in_one_request() {
+        let (result, files, progress) = upload(false).await;
+        result.unwrap();
+        let files = files.lock().unwrap();
+        assert_eq!(files.get("clip.mp4").unwrap(), b"0123456789");
+        assert_eq!(files.len(), 1);
+        assert_eq!(progress, Some(10));
+    }
+
+    #[tokio::test]
+    async fn webdav_rejects_incomplete_parts() {
+        let (result, files, _) = upload(true).await;
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("has 2 of 10 bytes"), "{error}");
+        // Neither the incomplete file nor its part are left behind
+        assert!(files.lock().unwrap().is_empty());
+    }
+}
@@ -15,9 +15,9 @@ fn format_write(buffer: &mut Formatter, record: &Record) -> std::io::Result<()>
     };
     let level_color = buffer.default_level_style(level);
     for result in record.args().to_string().split('\n').map(|line| {
-        write!(
+        writeln!(
             buffer,
-            "[{time} {bold}{level_color}{level:<5}{level_color:#}{bold:#}] {bold}{line}{bold:#}\n",
+            "[{time} {bold}{level_color}{level:<5}{level_color:#}{bold:#}] {bold}{line}{bold:#}",
             time = Local::now().format("%Y-%m-%d %H:%M:%S"),
         )
     }) {
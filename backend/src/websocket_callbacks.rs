@@ -1,6 +1,8 @@
 use crate::{
-    database::{Database, Recording},
-    websocket_connection::messages::ServerMessage,
+    auth::authorize_owner,
+    clip::cancel_clip,
+    database::{Database, Recording, User},
+    websocket_connection::messages::{ClientMessage, ServerMessage},
     ClientConnections,
 };
 
@@ -41,6 +43,8 @@ impl Display for CallbackError {
 #[derive(Clone)]
 pub struct MessageHandlerState {
     pub client_id: usize,
+    /// Who signed in when connecting, `None` when anonymous
+    pub user: Option<User>,
     pub clients: ClientConnections,
     // Although [`Database`] implements Clone, it doesn't implement Send + Sync
     pub database: Arc<Mutex<Database>>,
@@ -58,10 +62,32 @@ pub fn on_disconnect(_state: Arc<MessageHandlerState>) -> AsynchronousMessageHan
     Box::pin(async move { Ok(None) })
 }
 pub fn on_message(
-    _state: Arc<MessageHandlerState>,
-    _message: Message,
+    state: Arc<MessageHandlerState>,
+    message: Message,
 ) -> AsynchronousMessageHandlerResponse {
-    Box::pin(async move { Ok(None) })
+    Box::pin(async move {
+        let Ok(text) = message.to_str() else {
+            return Ok(None);
+        };
+        let message = serde_json::from_str::<ClientMessage>(text)
+            .map_err(|e| anyhow!("invalid message: {e}"))?;
+        match message {
+            ClientMessage::Cancel(uuid) => {
+                let recording = state
+                    .database
+                    .lock()
+                    .await
+                    .get_recording(&uuid)?
+                    .ok_or_else(|| anyhow!("no recording {uuid}"))?;
+                authorize_owner(state.user.as_ref(), &recording)
+                    .map_err(|e| anyhow!(e.message()))?;
+                if !cancel_clip(&uuid).await {
+                    Err(anyhow!("{uuid} isn't queued or running"))?;
+                }
+            }
+        }
+        Ok(None)
+    })
 }
 
 /// Broadcast a recording row to all websocket clients
@@ -69,17 +95,27 @@ pub async fn alert_clients_of_database_change(
     clients: ClientConnections,
     change: &Recording,
 ) -> Result<()> {
+    broadcast(
+        clients,
+        &ServerMessage::DatabaseUpdate(Box::new(change.clone())),
+    )
+    .await
+}
+
+/// Tell all websocket clients that a recording is gone
+pub async fn alert_clients_of_deletion(clients: ClientConnections, uuid: &str) -> Result<()> {
+    broadcast(clients, &ServerMessage::RecordingDeleted(uuid.to_string())).await
+}
+
+/// Send a message to all websocket clients
+async fn broadcast(clients: ClientConnections, message: &ServerMessage) -> Result<()> {
+    let serialized = serde_json::to_string(message)?;
     let clients = clients.read().await;
     let errors = clients
         .iter()
         .filter_map(|(id, client)| {
-            let serialized =
-                match serde_json::to_string(&ServerMessage::DatabaseUpdate(change.clone())) {
-                    Ok(serialized) => serialized,
-                    Err(e) => return Some((*id, e.into())),
-                };
             client
-                .send(Message::text(serialized))
+                .send(Message::text(serialized.clone()))
                 .context("sending")
                 .err()
                 .map(|e| (*id, e))
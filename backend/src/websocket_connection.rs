@@ -1,7 +1,7 @@
 //! Facilitates a client's connection
 
 use crate::{
-    database::Database,
+    database::{Database, User},
     websocket_callbacks::{
         AsynchronousMessageHandlerResponse, CallbackError, MessageHandlerResponse,
         MessageHandlerState,
@@ -37,9 +37,17 @@ pub mod messages {
     #[derive(serde::Serialize)]
     pub enum ServerMessage {
         ClientHello,
-        DatabaseUpdate(Recording),
+        DatabaseUpdate(Box<Recording>),
+        /// A recording was deleted, by UUID
+        RecordingDeleted(String),
         Error(String),
     }
+
+    #[derive(serde::Deserialize)]
+    pub enum ClientMessage {
+        /// Cancel a queued or running clip by UUID
+        Cancel(String),
+    }
 }
 
 /// Bridge for a client's connection that listens for messages and allows for them
@@ -57,6 +65,7 @@ pub async fn handle_connection<C, D, M>(
     on_disconnect_callback: D,
     on_message_callback: M,
     /* State */
+    user: Option<User>,
     clients: ClientConnections,
     database: Database,
 ) where
@@ -75,6 +84,7 @@ pub async fn handle_connection<C, D, M>(
     let callback_state = Arc::new(MessageHandlerState {
         clients: Arc::clone(&clients),
         client_id,
+        user,
         database: Arc::new(Mutex::new(database)),
     });
     let callback_handle = |response: MessageHandlerResponse, callback: &'static str| match response
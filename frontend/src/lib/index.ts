@@ -27,6 +27,7 @@ export enum Stage {
     "Combining Failed" = 12,
     "Encoding Failed" = 13,
     "Uploading Failed" = 14,
+    "Cancelled" = 15,
 }
 
 // prettier-ignore